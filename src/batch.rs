@@ -0,0 +1,106 @@
+/*
+ * batch.rs
+ *
+ * striking-db - Persistent key/value store for SSDs.
+ * Copyright (c) 2017 Maxwell Duzen, Ammon Smith
+ *
+ * striking-db is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Lesser General Public License as
+ * published by the Free Software Foundation, either version 2 of
+ * the License, or (at your option) any later version.
+ *
+ * striking-db is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public
+ * License along with striking-db.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+use std::collections::BTreeMap;
+use std::collections::btree_map;
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+pub enum Operation {
+    Put(Vec<u8>),
+    Insert(Vec<u8>),
+    Remove,
+}
+
+/// A set of changes to be applied to the datastore atomically.
+/// See [`Store::write_batch`].
+///
+/// If more than one change is made to the same key, only
+/// the last one is kept.
+///
+/// [`Store::write_batch`]: struct.Store.html#method.write_batch
+#[derive(Debug, Default, Clone, Hash, PartialEq, Eq)]
+pub struct WriteBatch(BTreeMap<Box<[u8]>, Operation>);
+
+impl WriteBatch {
+    /// Creates a new, empty `WriteBatch`.
+    pub fn new() -> Self {
+        WriteBatch::default()
+    }
+
+    fn add(&mut self, key: &[u8], op: Operation) -> &mut Self {
+        let key = Vec::from(key).into_boxed_slice();
+        self.0.insert(key, op);
+        self
+    }
+
+    /// Adds an item to place into the datastore, regardless of whether
+    /// or not it exists, and returns `&mut self` for chaining methods.
+    /// See [`Store::put`].
+    ///
+    /// [`Store::put`]: struct.Store.html#method.put
+    pub fn put(&mut self, key: &[u8], val: &[u8]) -> &mut Self {
+        self.add(key, Operation::Put(Vec::from(val)))
+    }
+
+    /// Adds an item to insert into the datastore, and returns
+    /// `&mut self` for chaining methods. If the item already
+    /// exists, the whole batch fails. See [`Store::insert`].
+    ///
+    /// [`Store::insert`]: struct.Store.html#method.insert
+    pub fn insert(&mut self, key: &[u8], val: &[u8]) -> &mut Self {
+        self.add(key, Operation::Insert(Vec::from(val)))
+    }
+
+    /// Adds an item to remove from the datastore, and returns
+    /// `&mut self` for chaining methods. See [`Store::remove`].
+    ///
+    /// [`Store::remove`]: struct.Store.html#method.remove
+    pub fn remove(&mut self, key: &[u8]) -> &mut Self {
+        self.add(key, Operation::Remove)
+    }
+
+    /// Returns the number of changes in the batch.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Returns `true` if there are no changes in the batch.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Removes all changes from the batch.
+    #[inline]
+    pub fn clear(&mut self) {
+        self.0.clear();
+    }
+
+    pub(crate) fn get(&self, key: &[u8]) -> Option<&Operation> {
+        self.0.get(key)
+    }
+
+    // In key order
+    pub(crate) fn iter(&self) -> btree_map::Iter<Box<[u8]>, Operation> {
+        self.0.iter()
+    }
+}
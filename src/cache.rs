@@ -19,66 +19,500 @@
  *
  */
 
-use lru_time_cache::LruCache;
-use parking_lot::RwLock;
-use std::cmp::min;
+use error::Result;
+use num_cpus;
+use parking_lot::{Mutex, RwLock};
+use serial::ReadContext;
+use std::collections::{HashMap, VecDeque};
+use std::collections::hash_map::DefaultHasher;
 use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::sync::Arc;
+use stats::Stats;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use store::Store;
 
-pub struct ReadCache(RwLock<LruCache<Box<[u8]>, Box<[u8]>>>);
+// Used when OpenOptions::read_cache is not set
+pub const DEFAULT_CAPACITY: usize = 32 * 1024 * 1024; /* 32 MiB */
+
+// Used when OpenOptions::read_cache_max_value is not set
+pub const DEFAULT_MAX_VALUE: usize = 64 * 1024; /* 64 KiB */
+
+// An estimate of the memory used to track each entry,
+// apart from its key and value
+const ENTRY_OVERHEAD: usize = 64;
+
+// Each shard should be able to hold this many of the largest values
+const MIN_SHARD_VALUES: usize = 4;
+
+// Used to size each shard's frequency sketch, which
+// has a counter for roughly every entry that could fit
+const AVERAGE_ENTRY: usize = 256;
+
+// Seeds for the sketch's rows, so that each hashes keys differently
+const SEEDS: [u64; 4] = [
+    0xc3a5_c85c_97cb_3127,
+    0xb492_b66f_be98_f273,
+    0x9ae1_6a3b_2f90_404f,
+    0xcbf2_9ce4_8422_2325,
+];
+
+// Counters stop at this, since only the relative frequency matters
+const MAX_COUNT: u8 = 15;
+
+/// A shared reference to the value of an item. See [`Store::get_ref`].
+///
+/// The value is shared with the read cache, so getting it again does not
+/// need to allocate. It stays valid even if the item is later changed.
+///
+/// [`Store::get_ref`]: struct.Store.html#method.get_ref
+#[derive(Debug, Clone)]
+pub struct ValueRef(Arc<[u8]>);
+
+impl ValueRef {
+    // For values that are not being cached
+    pub(crate) fn new(val: &[u8]) -> Self {
+        ValueRef(Arc::from(val))
+    }
+}
+
+impl Deref for ValueRef {
+    type Target = [u8];
+
+    #[inline]
+    fn deref(&self) -> &[u8] {
+        &*self.0
+    }
+}
+
+impl AsRef<[u8]> for ValueRef {
+    #[inline]
+    fn as_ref(&self) -> &[u8] {
+        &*self.0
+    }
+}
+
+/// A handle whose reads don't add items to the read cache.
+/// See [`Store::uncached`].
+///
+/// It dereferences to a [`Store`] with the same keyspace, and its
+/// iterators start with [`Range::fill_cache`] turned off.
+///
+/// [`Store`]: struct.Store.html
+/// [`Store::uncached`]: struct.Store.html#method.uncached
+/// [`Range::fill_cache`]: struct.Range.html#method.fill_cache
+#[derive(Debug)]
+pub struct Uncached<'s> {
+    store: Store,
+    phantom: PhantomData<&'s Store>,
+}
+
+impl<'s> Uncached<'s> {
+    pub(crate) fn new(store: Store) -> Self {
+        Uncached {
+            store: store,
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<'s> Deref for Uncached<'s> {
+    type Target = Store;
+
+    #[inline]
+    fn deref(&self) -> &Store {
+        &self.store
+    }
+}
+
+// A count-min sketch estimating how often each key has been asked for,
+// including the times it was not cached. Counters are halved once enough
+// accesses have been recorded, so that keys that are no longer in use
+// lose their standing.
+struct Sketch {
+    table: Box<[u8]>,
+    mask: usize,
+    additions: usize,
+    sample: usize,
+}
+
+impl Sketch {
+    fn new(counters: usize) -> Self {
+        let width = counters.next_power_of_two();
+
+        Sketch {
+            table: vec![0; width * SEEDS.len()].into_boxed_slice(),
+            mask: width - 1,
+            additions: 0,
+            sample: width * 10,
+        }
+    }
+
+    #[inline]
+    fn slot(&self, row: usize, hash: u64) -> usize {
+        let idx = (hash.wrapping_mul(SEEDS[row]) >> 32) as usize & self.mask;
+        row * (self.mask + 1) + idx
+    }
+
+    fn increment(&mut self, hash: u64) {
+        for row in 0..SEEDS.len() {
+            let slot = self.slot(row, hash);
+            if self.table[slot] < MAX_COUNT {
+                self.table[slot] += 1;
+            }
+        }
+
+        self.additions += 1;
+        if self.additions >= self.sample {
+            for count in self.table.iter_mut() {
+                *count /= 2;
+            }
+
+            self.additions /= 2;
+        }
+    }
+
+    fn estimate(&self, hash: u64) -> u8 {
+        (0..SEEDS.len())
+            .map(|row| self.table[self.slot(row, hash)])
+            .min()
+            .unwrap_or(0)
+    }
+}
+
+struct Entry {
+    val: Arc<[u8]>,
+    hash: u64,
+    size: usize,
+    serial: u64,
+
+    // Set on every hit, and cleared as the clock hand passes
+    referenced: AtomicBool,
+}
+
+// Evicts using CLOCK, so that hits only need a shared lock. The ring
+// holds keys in the order they were inserted, and entries that were
+// hit since the hand last passed are given another lap. The serial
+// number tells apart slots left behind by removed entries.
+struct Shard {
+    entries: HashMap<Box<[u8]>, Entry>,
+    ring: VecDeque<(u64, Box<[u8]>)>,
+    sketch: Mutex<Sketch>,
+    serial: u64,
+    used: usize,
+
+    // Lookups only hold a shared lock, so these are atomic
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+    insertions: u64,
+    evictions: u64,
+}
+
+impl Shard {
+    fn new(capacity: usize) -> Self {
+        Shard {
+            entries: HashMap::new(),
+            ring: VecDeque::new(),
+            sketch: Mutex::new(Sketch::new(capacity / AVERAGE_ENTRY + 1)),
+            serial: 0,
+            used: 0,
+            hits: AtomicUsize::new(0),
+            misses: AtomicUsize::new(0),
+            insertions: 0,
+            evictions: 0,
+        }
+    }
+
+    fn get(&self, key: &[u8], hash: u64) -> Option<Arc<[u8]>> {
+        let val = self.entries.get(key).map(|entry| {
+            entry.referenced.store(true, Ordering::Relaxed);
+            entry.val.clone()
+        });
+        self.count(val.is_some());
+
+        // Misses go to disk anyway, but hits shouldn't wait on each other
+        // just to be counted. The sketch is only an estimate regardless.
+        if val.is_none() {
+            self.sketch.lock().increment(hash);
+        } else if let Some(mut sketch) = self.sketch.try_lock() {
+            sketch.increment(hash);
+        }
+
+        val
+    }
+
+    // Unlike get(), this is not counted as a use of the key
+    fn peek(&self, key: &[u8]) -> Option<Arc<[u8]>> {
+        let val = self.entries.get(key).map(|entry| entry.val.clone());
+        self.count(val.is_some());
+        val
+    }
 
-impl ReadCache {
     #[inline]
-    pub fn new() -> Self {
-        Self::with_capacity(512)
+    fn count(&self, hit: bool) {
+        let counter = if hit { &self.hits } else { &self.misses };
+        counter.fetch_add(1, Ordering::Relaxed);
     }
 
-    pub fn with_capacity(items: usize) -> Self {
-        ReadCache(RwLock::new(LruCache::with_capacity(items)))
+    // A new key is only let in if it has been asked for more often than
+    // the entry it would replace. This keeps one-off reads, like those
+    // of a full scan, from pushing out the items that are actually hot.
+    fn admit(&mut self, hash: u64) -> bool {
+        let victim = match self.victim() {
+            Some(victim) => victim,
+            None => return true,
+        };
+
+        let sketch = self.sketch.lock();
+        sketch.estimate(hash) > sketch.estimate(victim)
     }
 
-    pub fn insert(&self, key: &[u8], val: &[u8]) -> Option<Box<[u8]>> {
-        self.0.write().insert(
-            Vec::from(key).into_boxed_slice(),
-            Vec::from(val).into_boxed_slice(),
-        )
+    fn insert(&mut self, key: Box<[u8]>, val: Arc<[u8]>, hash: u64, size: usize) {
+        self.remove(&key);
+        self.serial += 1;
+        self.used += size;
+        self.insertions += 1;
+
+        let entry = Entry {
+            val: val,
+            hash: hash,
+            size: size,
+            serial: self.serial,
+            referenced: AtomicBool::new(false),
+        };
+
+        self.ring.push_back((self.serial, key.clone()));
+        self.entries.insert(key, entry);
     }
 
-    pub fn get(&self, key: &[u8], val: &mut [u8]) -> Option<usize> {
-        self.0.write().get(key).map(move |slice| {
-            let slice = &**slice;
-            let len = min(val.len(), slice.len());
+    fn remove(&mut self, key: &[u8]) -> Option<Arc<[u8]>> {
+        let entry = match self.entries.remove(key) {
+            Some(entry) => entry,
+            None => return None,
+        };
+
+        self.used -= entry.size;
 
-            let dest = &mut val[..len];
-            let src = &slice[..len];
-            dest.copy_from_slice(src);
+        // Don't let stale slots build up in the ring
+        if self.ring.len() > self.entries.len() * 2 + 16 {
+            let entries = &self.entries;
+            let ring: VecDeque<_> = self.ring
+                .drain(..)
+                .filter(|&(serial, ref key)| {
+                    entries.get(key).map_or(false, |entry| entry.serial == serial)
+                })
+                .collect();
 
-            len
-        })
+            self.ring = ring;
+        }
+
+        Some(entry.val)
     }
 
-    pub fn remove(&self, key: &[u8]) -> Option<Box<[u8]>> {
-        self.0.write().remove(key)
+    // Moves the hand to the next entry to be evicted, and returns its hash
+    fn victim(&mut self) -> Option<u64> {
+        loop {
+            let (hash, referenced) = {
+                let &(serial, ref key) = match self.ring.front() {
+                    Some(slot) => slot,
+                    None => return None,
+                };
+
+                match self.entries.get(key) {
+                    Some(entry) if entry.serial == serial => {
+                        (Some(entry.hash), entry.referenced.swap(false, Ordering::Relaxed))
+                    }
+                    _ => (None, false),
+                }
+            };
+
+            match hash {
+                Some(hash) if !referenced => return Some(hash),
+                Some(_) => {
+                    let slot = self.ring.pop_front().unwrap();
+                    self.ring.push_back(slot);
+                }
+                None => {
+                    self.ring.pop_front();
+                }
+            }
+        }
+    }
+
+    // Returns false if there was nothing to evict
+    fn evict(&mut self) -> bool {
+        if self.victim().is_none() {
+            return false;
+        }
+
+        let (_, key) = self.ring.pop_front().unwrap();
+        let entry = self.entries.remove(&key).expect("Victim disappeared");
+        self.used -= entry.size;
+        self.evictions += 1;
+        true
+    }
+}
+
+// Holds up to the given number of bytes, counting each entry's key and
+// value along with an estimate of its overhead. Shared by every keyspace,
+// so each key is prefixed by its keyspace's id.
+//
+// Keys are spread across shards by hash, each with its own lock and
+// an even part of the capacity, so readers rarely contend.
+pub struct ReadCache {
+    shards: Box<[RwLock<Shard>]>,
+    shard_capacity: usize,
+    max_value: usize,
+}
+
+impl ReadCache {
+    pub fn new(capacity: usize, max_value: usize) -> Self {
+        let largest = max_value + ENTRY_OVERHEAD;
+        let mut count = (num_cpus::get() * 4).next_power_of_two();
+        while count > 1 && capacity / count < largest * MIN_SHARD_VALUES {
+            count /= 2;
+        }
+
+        let shard_capacity = capacity / count;
+        let shards: Vec<_> = (0..count)
+            .map(|_| RwLock::new(Shard::new(shard_capacity)))
+            .collect();
+
+        ReadCache {
+            shards: shards.into_boxed_slice(),
+            shard_capacity: shard_capacity,
+            max_value: max_value,
+        }
+    }
+
+    fn shard(&self, key: &[u8]) -> (&RwLock<Shard>, u64) {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        let hash = hasher.finish();
+
+        // The count is always a power of two
+        let idx = hash as usize & (self.shards.len() - 1);
+        (&self.shards[idx], hash)
+    }
+
+    // Returns the cached copy of the value. Values larger than the
+    // limit, or that aren't admitted, are returned without being cached.
+    pub fn insert(&self, space: u32, key: &[u8], val: &[u8]) -> ValueRef {
+        let val = Arc::<[u8]>::from(val);
+        if val.len() > self.max_value {
+            return ValueRef(val);
+        }
+
+        let key = cache_key(space, key).into_boxed_slice();
+        let size = key.len() * 2 + val.len() + ENTRY_OVERHEAD;
+        if size > self.shard_capacity {
+            return ValueRef(val);
+        }
+
+        let (shard, hash) = self.shard(&key);
+        let mut shard = shard.write();
+        let replaced = shard.remove(&key).is_some();
+
+        if !replaced && shard.used + size > self.shard_capacity && !shard.admit(hash) {
+            return ValueRef(val);
+        }
+
+        while shard.used + size > self.shard_capacity {
+            if !shard.evict() {
+                break;
+            }
+        }
+
+        shard.insert(key, val.clone(), hash, size);
+        ValueRef(val)
+    }
+
+    // Decides which items read from disk are cached, so that every kind
+    // of read follows the same rule: only if the read fills the cache,
+    // and only if the value is small enough to be kept. Large values
+    // aren't even read. Returns the value if it was read to be cached.
+    pub fn fill(&self, fill: bool, space: u32, ctx: &ReadContext) -> Result<Option<ValueRef>> {
+        if !fill || ctx.val_len()? > self.max_value {
+            return Ok(None);
+        }
+
+        let val = ctx.val()?;
+        Ok(Some(self.insert(space, ctx.key()?, &val)))
+    }
+
+    pub fn get_ref(&self, space: u32, key: &[u8]) -> Option<ValueRef> {
+        let key = cache_key(space, key);
+        let (shard, hash) = self.shard(&key);
+        shard.read().get(&key, hash).map(ValueRef)
+    }
+
+    // Looks up the value without counting it as a use, so
+    // that reads which opted out of caching don't affect it
+    pub fn peek(&self, space: u32, key: &[u8]) -> Option<ValueRef> {
+        let key = cache_key(space, key);
+        let (shard, _) = self.shard(&key);
+        shard.read().peek(&key).map(ValueRef)
+    }
+
+    pub fn remove(&self, space: u32, key: &[u8]) -> Option<Arc<[u8]>> {
+        let key = cache_key(space, key);
+        let (shard, _) = self.shard(&key);
+        shard.write().remove(&key)
+    }
+
+    // Only the cache's fields are set
+    pub fn stats(&self) -> Stats {
+        let mut stats = Stats::default();
+
+        for shard in self.shards.iter() {
+            let shard = shard.read();
+            stats.cache_hits += shard.hits.load(Ordering::Relaxed) as u64;
+            stats.cache_misses += shard.misses.load(Ordering::Relaxed) as u64;
+            stats.cache_insertions += shard.insertions;
+            stats.cache_evictions += shard.evictions;
+            stats.cache_bytes += shard.used as u64;
+        }
+
+        stats
     }
 
     #[allow(unused)]
     pub fn clear(&self) {
-        self.0.write().clear();
+        for shard in self.shards.iter() {
+            *shard.write() = Shard::new(self.shard_capacity);
+        }
     }
 }
 
+fn cache_key(space: u32, key: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(4 + key.len());
+    for i in 0..4 {
+        buf.push((space >> (i * 8)) as u8);
+    }
+
+    buf.extend_from_slice(key);
+    buf
+}
+
 impl fmt::Debug for ReadCache {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let mut cache = self.0.write();
-        let mut first = true;
+        let mut entries = 0;
+        let mut used = 0;
 
-        write!(f, "ReadCache({{")?;
-        for (key, val) in cache.iter() {
-            let comma = if first { "" } else { ", " };
-            write!(f, "{}{:?}: {:?}", comma, &*key, &*val)?;
-            first = false;
+        for shard in self.shards.iter() {
+            let shard = shard.read();
+            entries += shard.entries.len();
+            used += shard.used;
         }
-        write!(f, "}})")?;
 
-        Ok(())
+        f.debug_struct("ReadCache")
+            .field("shards", &self.shards.len())
+            .field("entries", &entries)
+            .field("used", &used)
+            .field("capacity", &(self.shard_capacity * self.shards.len()))
+            .field("max_value", &self.max_value)
+            .finish()
     }
 }
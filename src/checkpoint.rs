@@ -0,0 +1,103 @@
+/*
+ * checkpoint.rs
+ *
+ * striking-db - Persistent key/value store for SSDs.
+ * Copyright (c) 2017 Maxwell Duzen, Ammon Smith
+ *
+ * striking-db is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Lesser General Public License as
+ * published by the Free Software Foundation, either version 2 of
+ * the License, or (at your option) any later version.
+ *
+ * striking-db is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public
+ * License along with striking-db.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+use super::Result;
+use deleted::Deleted;
+use keyspace::Spaces;
+use serial::DatastoreState;
+use std::sync::Arc;
+use std::time::Duration;
+use volume::Volume;
+use worker::Worker;
+
+#[derive(Debug, Copy, Clone)]
+pub struct Checkpoint<'s> {
+    pub volume: &'s Volume<'static>,
+    pub spaces: &'s Spaces,
+    pub deleted: &'s Deleted,
+}
+
+impl<'s> Checkpoint<'s> {
+    pub fn run(&self) -> Result<()> {
+        // Only copies are made while writers are blocked,
+        // so that they don't have to wait for serialization.
+        let (spaces, deleted, next_keyspace, sequence, positions) = {
+            // An item written before the positions are taken, but not yet
+            // in the index, would be missing from both the state and the replay.
+            // Keyspaces can't be added or dropped while the registry is read.
+            let registry = self.spaces.read();
+            let spaces = registry.all();
+            let _frozen = spaces
+                .iter()
+                .map(|space| space.index.freeze())
+                .collect::<Vec<_>>();
+
+            let copies = spaces
+                .iter()
+                .map(|space| {
+                    (space.id(), space.index.read().clone(), space.expiry.map())
+                })
+                .collect::<Vec<_>>();
+
+            let deleted = self.deleted.read().clone();
+            let next_keyspace = registry.next_id();
+            let sequence = self.volume.sequence();
+            let positions = self.volume.positions();
+
+            (copies, deleted, next_keyspace, sequence, positions)
+        };
+
+        let trees = spaces
+            .iter()
+            .map(|&(id, ref index, ref expiry)| (id, index, expiry))
+            .collect::<Vec<_>>();
+
+        let state = DatastoreState::new(&trees, &deleted, next_keyspace, sequence, &positions)?;
+
+        // The previous state is now garbage
+        if let Some(old_ptr) = self.volume.save_state(state)? {
+            self.volume.read(old_ptr, |strand| {
+                strand.stats.lock().deleted_items += 1;
+            });
+            self.deleted.add(old_ptr);
+        }
+
+        Ok(())
+    }
+}
+
+// Runs in the background, saving a checkpoint once every interval
+pub fn spawn_worker(
+    volume: Arc<Volume<'static>>,
+    spaces: Arc<Spaces>,
+    deleted: Arc<Deleted>,
+    interval: Duration,
+) -> Worker {
+    Worker::spawn(interval, move |_| {
+        let checkpoint = Checkpoint {
+            volume: &*volume,
+            spaces: &*spaces,
+            deleted: &*deleted,
+        };
+
+        checkpoint.run()
+    })
+}
@@ -20,7 +20,7 @@
  */
 
 use super::FilePointer;
-use parking_lot::RwLock;
+use parking_lot::{RwLock, RwLockReadGuard};
 use std::collections::BTreeSet;
 
 pub type DeletedSet = BTreeSet<FilePointer>;
@@ -42,8 +42,35 @@ impl Deleted {
         assert_eq!(exists, true, "Deleted item already tracked");
     }
 
-    pub fn get_mut(&mut self) -> &mut DeletedSet {
-        self.0.get_mut()
+    pub fn contains(&self, value: FilePointer) -> bool {
+        self.0.read().contains(&value)
+    }
+
+    pub fn range(&self, start: FilePointer, end: FilePointer) -> Vec<FilePointer> {
+        self.0.read().range(start..end).cloned().collect()
+    }
+
+    pub fn outside(&self, start: FilePointer, end: FilePointer) -> Vec<FilePointer> {
+        let set = self.0.read();
+        let mut ptrs = Vec::new();
+        ptrs.extend(set.range(..start).cloned());
+        ptrs.extend(set.range(end..).cloned());
+        ptrs
+    }
+
+    pub fn remove_range(&self, start: FilePointer, end: FilePointer) -> usize {
+        let mut set = self.0.write();
+        let ptrs = set.range(start..end).cloned().collect::<Vec<_>>();
+
+        for ptr in ptrs.iter() {
+            set.remove(ptr);
+        }
+
+        ptrs.len()
+    }
+
+    pub fn read(&self) -> RwLockReadGuard<DeletedSet> {
+        self.0.read()
     }
 }
 
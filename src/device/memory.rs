@@ -23,10 +23,15 @@ use super::{Device, Result};
 use super::{check_read, check_write, check_trim};
 use parking_lot::RwLock;
 
+/// A [`Device`] held entirely in memory. Its contents are lost when it
+/// is dropped.
+///
+/// [`Device`]: trait.Device.html
 #[derive(Debug)]
 pub struct Memory(RwLock<Box<[u8]>>, u64);
 
 impl Memory {
+    /// Creates a device of the given size, in bytes, filled with zeroes.
     pub fn new(bytes: usize) -> Self {
         let buffer = vec![0; bytes].into_boxed_slice();
         Memory(RwLock::new(buffer), bytes as u64)
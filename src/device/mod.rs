@@ -37,16 +37,72 @@ cfg_if! {
     }
 }
 
-pub trait Device: Debug {
+/// A backend that a datastore is stored on. See [`Store::with_device`].
+///
+/// The device is treated as a flat array of bytes, `capacity()` long.
+/// Every access made by the store follows these alignment rules:
+///
+/// * Reads and writes start at a multiple of [`PAGE_SIZE`], and their
+///   length is a multiple of it as well. See [`check_read`] and
+///   [`check_write`].
+/// * Trims start at a multiple of [`TRIM_SIZE`], and their length is a
+///   multiple of it as well. See [`check_trim`].
+/// * No access extends past the end of the device.
+///
+/// Implementations may rely on these rules, and should call the check
+/// functions at the start of each method so that violations are caught.
+///
+/// Methods may be called from several threads at once, so any state
+/// must be synchronized by the device itself. A successful write must
+/// be visible to every read that follows it.
+///
+/// [`Store::with_device`]: struct.Store.html#method.with_device
+/// [`PAGE_SIZE`]: constant.PAGE_SIZE.html
+/// [`TRIM_SIZE`]: constant.TRIM_SIZE.html
+/// [`check_read`]: fn.check_read.html
+/// [`check_write`]: fn.check_write.html
+/// [`check_trim`]: fn.check_trim.html
+pub trait Device: Debug + Send + Sync {
+    /// The size of the device, in bytes. This must not change while
+    /// the device is in use, and must be a multiple of [`PAGE_SIZE`].
+    /// When opened with [`OpenMode::Truncate`], the device is trimmed
+    /// up to the last multiple of [`TRIM_SIZE`] within it.
+    ///
+    /// [`PAGE_SIZE`]: constant.PAGE_SIZE.html
+    /// [`TRIM_SIZE`]: constant.TRIM_SIZE.html
+    /// [`OpenMode::Truncate`]: enum.OpenMode.html
     fn capacity(&self) -> u64;
+
+    /// Whether this is a raw block device, as opposed
+    /// to a regular file or a region of memory.
     fn block_device(&self) -> bool;
+
+    /// Fills all of `buf` with the bytes starting at `off`.
+    /// If they can't all be read, an error is returned.
     fn read(&self, off: u64, buf: &mut [u8]) -> Result<()>;
+
+    /// Writes all of `buf` to the bytes starting at `off`.
+    /// If it can't all be written, an error is returned.
     fn write(&self, off: u64, buf: &[u8]) -> Result<()>;
+
+    /// Marks `len` bytes starting at `off` as no longer in use. Their
+    /// contents are undefined afterwards, and the store does not read
+    /// them again until they have been written. Devices that can't
+    /// discard data may do nothing.
     fn trim(&self, off: u64, len: u64) -> Result<()>;
 }
 
+/// Checks that a read follows the alignment rules of [`Device`].
+///
+/// # Panics
+///
+/// Panics if `off` or the length of `buf` is not a multiple of
+/// [`PAGE_SIZE`], or if the read goes past the end of the device.
+///
+/// [`Device`]: trait.Device.html
+/// [`PAGE_SIZE`]: constant.PAGE_SIZE.html
 #[inline(always)]
-fn check_read(dev: &Device, off: u64, buf: &[u8]) {
+pub fn check_read(dev: &Device, off: u64, buf: &[u8]) {
     let len = buf.len() as u64;
     assert_eq!(
         off % PAGE_SIZE64,
@@ -61,8 +117,17 @@ fn check_read(dev: &Device, off: u64, buf: &[u8]) {
     assert!(off + len <= dev.capacity(), "Read is out of bounds");
 }
 
+/// Checks that a write follows the alignment rules of [`Device`].
+///
+/// # Panics
+///
+/// Panics if `off` or the length of `buf` is not a multiple of
+/// [`PAGE_SIZE`], or if the write goes past the end of the device.
+///
+/// [`Device`]: trait.Device.html
+/// [`PAGE_SIZE`]: constant.PAGE_SIZE.html
 #[inline(always)]
-fn check_write(dev: &Device, off: u64, buf: &[u8]) {
+pub fn check_write(dev: &Device, off: u64, buf: &[u8]) {
     let len = buf.len() as u64;
     assert_eq!(
         off % PAGE_SIZE64,
@@ -77,8 +142,17 @@ fn check_write(dev: &Device, off: u64, buf: &[u8]) {
     assert!(off + len <= dev.capacity(), "Write is out of bounds");
 }
 
+/// Checks that a trim follows the alignment rules of [`Device`].
+///
+/// # Panics
+///
+/// Panics if `off` or `len` is not a multiple of [`TRIM_SIZE`],
+/// or if the trim goes past the end of the device.
+///
+/// [`Device`]: trait.Device.html
+/// [`TRIM_SIZE`]: constant.TRIM_SIZE.html
 #[inline(always)]
-fn check_trim(dev: &Device, off: u64, len: u64) {
+pub fn check_trim(dev: &Device, off: u64, len: u64) {
     assert_eq!(
         off % TRIM_SIZE64,
         0,
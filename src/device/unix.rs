@@ -34,6 +34,9 @@ mod ioctl {
     ioctl!(write_buf blkdiscard with BLK, 119; [u64; 2]);
 }
 
+/// A [`Device`] backed by a regular file or a raw block device.
+///
+/// [`Device`]: trait.Device.html
 #[derive(Debug)]
 pub struct Ssd {
     file: File,
@@ -64,6 +67,8 @@ impl Ssd {
         }
     }
 
+    /// Opens the file or block device at the given path for reading
+    /// and writing. Its capacity is rounded down to the page size.
     pub fn open(path: &Path) -> Result<Self> {
         let mut file = open_file(path)?;
         let (capacity, block) = Self::get_metadata(&mut file)?;
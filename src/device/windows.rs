@@ -26,6 +26,9 @@ use std::os::windows::prelude::*;
 use std::path::Path;
 use super::{Device, Result, open_file};
 
+/// A [`Device`] backed by a regular file or a raw block device.
+///
+/// [`Device`]: trait.Device.html
 #[derive(Debug)]
 pub struct Ssd {
     file: File,
@@ -39,6 +42,8 @@ impl Ssd {
         unimplemented!();
     }
 
+    /// Opens the file or block device at the given path for reading
+    /// and writing. Its capacity is rounded down to the page size.
     pub fn open(path: &Path) -> Result<Self> {
         let mut file = open_file(path)?;
         let (capacity, block) = Self::get_metadata()?;
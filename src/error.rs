@@ -20,6 +20,8 @@
  */
 
 use capnp;
+#[cfg(feature = "serde")]
+use serde::{de, ser};
 use std::{error, io, result};
 use std::fmt::{self, Display};
 
@@ -40,6 +42,11 @@ pub enum Error {
     /// the file has likely not been formatted before.
     Corrupt,
 
+    /// The item at the given location on the volume could not be decoded,
+    /// or its contents do not match its checksum. This is usually caused
+    /// by a torn write or a failing device.
+    CorruptItem(u64),
+
     /// An argument passed to a library function is invalid. A string
     /// describing what's wrong with the argument is also returned.
     BadArgument(&'static str),
@@ -58,12 +65,42 @@ pub enum Error {
     /// The item was not found in the datastore.
     ItemNotFound,
 
+    /// The current value of an item did not match the expected one.
+    /// See [`Store::compare_and_swap`].
+    ///
+    /// [`Store::compare_and_swap`]: struct.Store.html#method.compare_and_swap
+    Mismatch,
+
+    /// A transaction could not be committed, because an item it read
+    /// was changed by someone else first.
+    Conflict,
+
+    /// The keyspace has been dropped. See [`Store::drop_keyspace`].
+    ///
+    /// [`Store::drop_keyspace`]: struct.Store.html#method.drop_keyspace
+    KeyspaceNotFound,
+
     /// The key is invalid. Either it is too long, or it has a length of zero.
     InvalidKey,
 
     /// The value is invalid. It is too long.
     InvalidValue,
 
+    /// The value is longer than [`MAX_READ_LEN`], so it won't be read
+    /// into memory all at once. Read it in parts with [`Store::open_value`]
+    /// or [`Store::read_range`] instead.
+    ///
+    /// [`MAX_READ_LEN`]: constant.MAX_READ_LEN.html
+    /// [`Store::open_value`]: struct.Store.html#method.open_value
+    /// [`Store::read_range`]: struct.Store.html#method.read_range
+    ValueTooLarge,
+
+    /// A key or value could not be encoded or decoded. A message describing
+    /// the problem is also returned. See [`TypedStore`].
+    ///
+    /// [`TypedStore`]: struct.TypedStore.html
+    Codec(String),
+
     /// This library function, or some aspect of it, has not been implemented yet.
     Unimplemented,
 
@@ -85,12 +122,18 @@ impl error::Error for Error {
             &FileType => "Invalid file type",
             &OutOfSpace => "Volume is out of space",
             &Corrupt => "Volume is corrupt",
+            &CorruptItem(_) => "Item is corrupt",
             &BadArgument(desc) => desc,
             &IncompatibleVersion => "Volume is formatted with incompatible version",
             &ItemExists => "Item already exists",
             &ItemNotFound => "Item not found",
+            &Mismatch => "Item did not have the expected value",
+            &Conflict => "Transaction conflicted with another change",
+            &KeyspaceNotFound => "Keyspace has been dropped",
             &InvalidKey => "Specified key was invalid",
             &InvalidValue => "Specified value was invalid",
+            &ValueTooLarge => "Value is too large to read at once, use open_value or read_range",
+            &Codec(_) => "Key or value could not be encoded or decoded",
             &Unimplemented => "That operation isn't implemented yet",
             &Network => "General network error",
             &Io(Some(ref err)) => err.description(),
@@ -110,17 +153,44 @@ impl error::Error for Error {
 
 impl Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        use std::error::Error;
-        write!(f, "{}", self.description())
+        use std::error::Error as StdError;
+
+        match self {
+            &Error::CorruptItem(ptr) => write!(f, "{} at {:#x}", self.description(), ptr),
+            &Error::Codec(ref msg) => write!(f, "{}: {}", self.description(), msg),
+            _ => write!(f, "{}", self.description()),
+        }
     }
 }
 
 impl From<io::Error> for Error {
     fn from(err: io::Error) -> Self {
+        // Our own errors may have been passed through io::Read
+        let ours = match err.get_ref() {
+            Some(inner) => inner.is::<Error>(),
+            None => false,
+        };
+
+        if ours {
+            let inner = err.into_inner().expect("I/O error has no inner error");
+            return *inner.downcast::<Error>().expect("Inner error changed type");
+        }
+
         Error::Io(Some(err))
     }
 }
 
+// Lets our errors be passed through io::Read,
+// to be unwrapped again by the conversion above
+impl From<Error> for io::Error {
+    fn from(err: Error) -> Self {
+        match err {
+            Error::Io(Some(err)) => err,
+            err => io::Error::new(io::ErrorKind::InvalidData, err),
+        }
+    }
+}
+
 impl From<capnp::Error> for Error {
     fn from(err: capnp::Error) -> Self {
         use capnp::ErrorKind::*;
@@ -133,3 +203,17 @@ impl From<capnp::Error> for Error {
         }
     }
 }
+
+#[cfg(feature = "serde")]
+impl ser::Error for Error {
+    fn custom<T: Display>(msg: T) -> Self {
+        Error::Codec(msg.to_string())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl de::Error for Error {
+    fn custom<T: Display>(msg: T) -> Self {
+        Error::Codec(msg.to_string())
+    }
+}
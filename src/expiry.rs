@@ -0,0 +1,129 @@
+/*
+ * expiry.rs
+ *
+ * striking-db - Persistent key/value store for SSDs.
+ * Copyright (c) 2017 Maxwell Duzen, Ammon Smith
+ *
+ * striking-db is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Lesser General Public License as
+ * published by the Free Software Foundation, either version 2 of
+ * the License, or (at your option) any later version.
+ *
+ * striking-db is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public
+ * License along with striking-db.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+
+use parking_lot::RwLock;
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// When each key with a time-to-live expires, in milliseconds since the Unix epoch
+pub type ExpiryMap = BTreeMap<Box<[u8]>, u64>;
+
+#[derive(Debug, Default)]
+struct Deadlines {
+    by_key: ExpiryMap,
+    by_time: BTreeSet<(u64, Box<[u8]>)>,
+}
+
+// Tracks the keys that have a time-to-live. Expired keys are treated as
+// absent until they are removed by the vacuum. A key's expiry is cleared
+// whenever its item is replaced or removed.
+#[derive(Debug)]
+pub struct Expiry(RwLock<Deadlines>);
+
+impl Expiry {
+    pub fn new() -> Self {
+        Expiry(RwLock::new(Deadlines::default()))
+    }
+
+    pub fn from(map: ExpiryMap) -> Self {
+        let by_time = map.iter()
+            .map(|(key, &time)| (time, key.clone()))
+            .collect();
+
+        Expiry(RwLock::new(Deadlines {
+            by_key: map,
+            by_time: by_time,
+        }))
+    }
+
+    pub fn set(&self, key: &[u8], time: u64) {
+        let mut deadlines = self.0.write();
+        let key = Vec::from(key).into_boxed_slice();
+
+        if let Some(old_time) = deadlines.by_key.insert(key.clone(), time) {
+            deadlines.by_time.remove(&(old_time, key.clone()));
+        }
+
+        deadlines.by_time.insert((time, key));
+    }
+
+    pub fn clear(&self, key: &[u8]) {
+        let mut deadlines = self.0.write();
+
+        if let Some(time) = deadlines.by_key.remove(key) {
+            let key = Vec::from(key).into_boxed_slice();
+            deadlines.by_time.remove(&(time, key));
+        }
+    }
+
+    pub fn expired(&self, key: &[u8]) -> bool {
+        match self.0.read().by_key.get(key) {
+            Some(&time) => time <= now(),
+            None => false,
+        }
+    }
+
+    // Returns every key whose time-to-live has passed
+    pub fn due(&self) -> Vec<Box<[u8]>> {
+        let now = now();
+
+        self.0
+            .read()
+            .by_time
+            .iter()
+            .take_while(|&&(time, _)| time <= now)
+            .map(|&(_, ref key)| key.clone())
+            .collect()
+    }
+
+    pub fn map(&self) -> ExpiryMap {
+        self.0.read().by_key.clone()
+    }
+}
+
+impl Default for Expiry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// The current time, in milliseconds since the Unix epoch
+pub fn now() -> u64 {
+    let elapsed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or(Duration::from_secs(0));
+
+    millis(elapsed)
+}
+
+// When an item written now with the given time-to-live should expire
+pub fn deadline(ttl: Duration) -> u64 {
+    now().saturating_add(millis(ttl))
+}
+
+fn millis(duration: Duration) -> u64 {
+    let nanos = duration.subsec_nanos() as u64;
+    duration
+        .as_secs()
+        .saturating_mul(1000)
+        .saturating_add(nanos / 1_000_000)
+}
@@ -20,34 +20,42 @@
  */
 
 use super::{MAX_KEY_LEN, FilePointer};
-use parking_lot::RwLock;
+use parking_lot::{RwLock, RwLockReadGuard, RwLockWriteGuard};
 use std::collections::BTreeMap;
+use std::collections::Bound;
+use std::fmt;
 use std::marker::PhantomData;
+use std::mem;
 use std::thread;
 
 pub type IndexTree = BTreeMap<Box<[u8]>, (FilePointer, bool)>;
 
 #[must_use]
-#[derive(Debug)]
 pub struct IndexEntryGuard<'i, 'k> {
     phantom: PhantomData<&'i RwLock<IndexTree>>,
     index: *const RwLock<IndexTree>,
+    _gate: RwLockReadGuard<'i, ()>,
     key: &'k [u8],
     pub value: Option<FilePointer>,
 }
 
 impl<'i, 'k> IndexEntryGuard<'i, 'k> {
-    fn new(index: &'i RwLock<IndexTree>, key: &'k [u8], value: Option<FilePointer>) -> Self {
+    fn new(
+        index: &'i RwLock<IndexTree>,
+        gate: RwLockReadGuard<'i, ()>,
+        key: &'k [u8],
+        value: Option<FilePointer>,
+    ) -> Self {
         IndexEntryGuard {
             phantom: PhantomData,
             index: index,
+            _gate: gate,
             key: key,
             value: value,
         }
     }
 
     #[inline]
-    #[allow(unused)]
     pub fn key(&self) -> &[u8] {
         self.key
     }
@@ -81,8 +89,19 @@ impl<'i, 'k> Drop for IndexEntryGuard<'i, 'k> {
     }
 }
 
+impl<'i, 'k> fmt::Debug for IndexEntryGuard<'i, 'k> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("IndexEntryGuard")
+            .field("key", &self.key)
+            .field("value", &self.value)
+            .finish()
+    }
+}
+
+// The second lock is held for reading by every entry guard,
+// so that the index can be frozen while no changes are in flight.
 #[derive(Debug)]
-pub struct Index(RwLock<IndexTree>);
+pub struct Index(RwLock<IndexTree>, RwLock<()>);
 
 impl Index {
     fn tree_valid(map: &IndexTree) -> bool {
@@ -100,18 +119,49 @@ impl Index {
     }
 
     pub fn new() -> Self {
-        Index(RwLock::new(BTreeMap::new()))
+        Index(RwLock::new(BTreeMap::new()), RwLock::new(()))
     }
 
     pub fn from(map: IndexTree) -> Self {
         debug_assert!(Self::tree_valid(&map));
-        Index(RwLock::new(map))
+        Index(RwLock::new(map), RwLock::new(()))
     }
 
     pub fn exists(&self, key: &[u8]) -> bool {
         self.0.read().contains_key(key)
     }
 
+    // Finds the first key within the given bounds, or the last one if
+    // reverse is set. Keys that are still being inserted are skipped,
+    // which are locked with a null pointer.
+    pub fn next_key(
+        &self,
+        start: Bound<&[u8]>,
+        end: Bound<&[u8]>,
+        reverse: bool,
+    ) -> Option<Box<[u8]>> {
+        let map = self.0.read();
+        let mut range = map.range::<[u8], _>((start, end)).filter(
+            |&(_, &(ptr, locked))| !locked || ptr != 0,
+        );
+        let entry = if reverse {
+            range.next_back()
+        } else {
+            range.next()
+        };
+
+        entry.map(|(key, _)| key.clone())
+    }
+
+    pub fn keys_in_range(&self, start: FilePointer, end: FilePointer) -> Vec<Box<[u8]>> {
+        self.0
+            .read()
+            .iter()
+            .filter(|&(_, &(ptr, _))| start <= ptr && ptr < end)
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
     pub fn lock<'i, 'k>(&'i self, key: &'k [u8]) -> IndexEntryGuard<'i, 'k> {
         loop {
             if let Some(guard) = self.try_lock(key) {
@@ -123,6 +173,39 @@ impl Index {
     }
 
     pub fn try_lock<'i, 'k>(&'i self, key: &'k [u8]) -> Option<IndexEntryGuard<'i, 'k>> {
+        let gate = self.1.read();
+        self.acquire(key).map(
+            |value| IndexEntryGuard::new(&self.0, gate, key, value),
+        )
+    }
+
+    // Locks every given key, returning the guards in sorted order.
+    // Taking them in order means that two threads locking some of
+    // the same keys can't deadlock. The keys must be unique.
+    pub fn lock_all<'i, 'k>(&'i self, keys: &[&'k [u8]]) -> Vec<IndexEntryGuard<'i, 'k>> {
+        let mut keys = keys.to_vec();
+        keys.sort();
+
+        // The gate is already held while waiting for the later keys,
+        // so it has to be taken recursively to avoid deadlocking with
+        // a thread waiting in freeze().
+        let _gate = self.1.read();
+
+        keys.iter()
+            .map(|&key| loop {
+                if let Some(value) = self.acquire(key) {
+                    let gate = self.1.read_recursive();
+                    break IndexEntryGuard::new(&self.0, gate, key, value);
+                }
+
+                thread::yield_now();
+            })
+            .collect()
+    }
+
+    // Marks the entry as locked, returning its current value.
+    // Returns None if it is already locked.
+    fn acquire(&self, key: &[u8]) -> Option<Option<FilePointer>> {
         let mut map = self.0.write();
         let mut value = None;
 
@@ -146,11 +229,24 @@ impl Index {
             map.insert(key_box, (0, true));
         }
 
-        Some(IndexEntryGuard::new(&self.0, key.clone(), value))
+        Some(value)
+    }
+
+    // Waits for all locked entries to be released, and stops any
+    // more from being locked until the returned guard is dropped.
+    // Entries must not be locked on this thread while it is held.
+    pub fn freeze(&self) -> RwLockWriteGuard<()> {
+        self.1.write()
+    }
+
+    pub fn read(&self) -> RwLockReadGuard<IndexTree> {
+        self.0.read()
     }
 
-    pub fn get_mut(&mut self) -> &mut IndexTree {
-        self.0.get_mut()
+    // Empties the index, returning everything that was in it.
+    // Must only be called while the index is frozen.
+    pub fn drain(&self) -> IndexTree {
+        mem::replace(&mut *self.0.write(), BTreeMap::new())
     }
 }
 
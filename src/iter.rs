@@ -0,0 +1,327 @@
+/*
+ * iter.rs
+ *
+ * striking-db - Persistent key/value store for SSDs.
+ * Copyright (c) 2017 Maxwell Duzen, Ammon Smith
+ *
+ * striking-db is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Lesser General Public License as
+ * published by the Free Software Foundation, either version 2 of
+ * the License, or (at your option) any later version.
+ *
+ * striking-db is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public
+ * License along with striking-db.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+use super::Result;
+use cache::ReadCache;
+use expiry::Expiry;
+use index::Index;
+use keyspace::Space;
+use serial::read_item;
+use std::collections::{BTreeMap, Bound, btree_map};
+use std::collections::Bound::*;
+use std::ops;
+use volume::Volume;
+
+/// The bounds of a range of keys, as accepted by [`Store::range`].
+///
+/// This is implemented for the standard range types, with any key type
+/// that can be viewed as a byte slice, as well as for a pair of [`Bound`]s.
+///
+/// [`Store::range`]: struct.Store.html#method.range
+/// [`Bound`]: https://doc.rust-lang.org/stable/std/collections/enum.Bound.html
+pub trait KeyRange {
+    /// Returns the start and end bounds of the range.
+    fn bounds(&self) -> (Bound<&[u8]>, Bound<&[u8]>);
+}
+
+impl<K: AsRef<[u8]>> KeyRange for ops::Range<K> {
+    fn bounds(&self) -> (Bound<&[u8]>, Bound<&[u8]>) {
+        (Included(self.start.as_ref()), Excluded(self.end.as_ref()))
+    }
+}
+
+impl<K: AsRef<[u8]>> KeyRange for ops::RangeFrom<K> {
+    fn bounds(&self) -> (Bound<&[u8]>, Bound<&[u8]>) {
+        (Included(self.start.as_ref()), Unbounded)
+    }
+}
+
+impl<K: AsRef<[u8]>> KeyRange for ops::RangeTo<K> {
+    fn bounds(&self) -> (Bound<&[u8]>, Bound<&[u8]>) {
+        (Unbounded, Excluded(self.end.as_ref()))
+    }
+}
+
+impl KeyRange for ops::RangeFull {
+    fn bounds(&self) -> (Bound<&[u8]>, Bound<&[u8]>) {
+        (Unbounded, Unbounded)
+    }
+}
+
+impl<'a> KeyRange for (Bound<&'a [u8]>, Bound<&'a [u8]>) {
+    fn bounds(&self) -> (Bound<&[u8]>, Bound<&[u8]>) {
+        (self.0, self.1)
+    }
+}
+
+fn to_owned(bound: Bound<&[u8]>) -> Bound<Box<[u8]>> {
+    match bound {
+        Included(key) => Included(Vec::from(key).into_boxed_slice()),
+        Excluded(key) => Excluded(Vec::from(key).into_boxed_slice()),
+        Unbounded => Unbounded,
+    }
+}
+
+fn as_ref(bound: &Bound<Box<[u8]>>) -> Bound<&[u8]> {
+    match *bound {
+        Included(ref key) => Included(&**key),
+        Excluded(ref key) => Excluded(&**key),
+        Unbounded => Unbounded,
+    }
+}
+
+// The part of the key space that an iterator has yet to visit
+#[derive(Debug)]
+pub struct Bounds {
+    start: Bound<Box<[u8]>>,
+    end: Bound<Box<[u8]>>,
+}
+
+impl Bounds {
+    pub fn new<R: KeyRange>(range: &R) -> Self {
+        let (start, end) = range.bounds();
+
+        Bounds {
+            start: to_owned(start),
+            end: to_owned(end),
+        }
+    }
+
+    // Every key that begins with the prefix is below the
+    // prefix with its last non-0xff byte incremented.
+    pub fn prefix(prefix: &[u8]) -> Self {
+        let mut end = Vec::from(prefix);
+        let end = match end.iter().rposition(|&byte| byte != 0xff) {
+            Some(idx) => {
+                end.truncate(idx + 1);
+                end[idx] += 1;
+                Excluded(end.into_boxed_slice())
+            }
+            None => Unbounded,
+        };
+
+        Bounds {
+            start: Included(Vec::from(prefix).into_boxed_slice()),
+            end: end,
+        }
+    }
+
+    // BTreeMap::range() panics if the bounds cross
+    pub fn exhausted(&self) -> bool {
+        match (&self.start, &self.end) {
+            (&Included(ref start), &Included(ref end)) => start > end,
+            (&Included(ref start), &Excluded(ref end)) |
+            (&Excluded(ref start), &Included(ref end)) |
+            (&Excluded(ref start), &Excluded(ref end)) => start >= end,
+            _ => false,
+        }
+    }
+
+    // The entries of the map within the bounds
+    pub fn entries<'a, V>(
+        &self,
+        map: &'a BTreeMap<Box<[u8]>, V>,
+    ) -> Option<btree_map::Range<'a, Box<[u8]>, V>> {
+        if self.exhausted() {
+            None
+        } else {
+            Some(map.range::<[u8], _>(
+                (as_ref(&self.start), as_ref(&self.end)),
+            ))
+        }
+    }
+
+    // Takes the first remaining key, or the last one if
+    // reverse is set, so that it will not be visited again.
+    fn next_key(&mut self, index: &Index, reverse: bool) -> Option<Box<[u8]>> {
+        if self.exhausted() {
+            return None;
+        }
+
+        let key = index.next_key(as_ref(&self.start), as_ref(&self.end), reverse);
+        if let Some(ref key) = key {
+            if reverse {
+                self.end = Excluded(key.clone());
+            } else {
+                self.start = Excluded(key.clone());
+            }
+        }
+
+        key
+    }
+}
+
+/// An iterator over a range of items in the datastore, in key order.
+/// It yields the key and value of each item.
+///
+/// This struct is created by [`Store::range`] and [`Store::scan_prefix`].
+/// It may also be iterated in reverse, and from both ends at once.
+///
+/// Items it reads from disk are offered to the read cache, unless this
+/// was turned off with [`fill_cache`] or the iterator came from
+/// [`Store::uncached`].
+///
+/// [`Store::range`]: struct.Store.html#method.range
+/// [`Store::scan_prefix`]: struct.Store.html#method.scan_prefix
+/// [`Store::uncached`]: struct.Store.html#method.uncached
+/// [`fill_cache`]: #method.fill_cache
+#[derive(Debug)]
+pub struct Range<'s> {
+    volume: &'s Volume<'static>,
+    space: &'s Space,
+    cache: &'s ReadCache,
+    verify: bool,
+    fill_cache: bool,
+    bounds: Bounds,
+}
+
+impl<'s> Range<'s> {
+    pub(crate) fn new(
+        volume: &'s Volume<'static>,
+        space: &'s Space,
+        cache: &'s ReadCache,
+        verify: bool,
+        fill_cache: bool,
+        bounds: Bounds,
+    ) -> Self {
+        Range {
+            volume: volume,
+            space: space,
+            cache: cache,
+            verify: verify,
+            fill_cache: fill_cache,
+            bounds: bounds,
+        }
+    }
+
+    /// Sets whether items read by the iterator are added to the read
+    /// cache, and returns the iterator. Turning this off keeps a large
+    /// scan from affecting which items stay cached, though values that
+    /// are already cached are still used.
+    pub fn fill_cache(mut self, fill: bool) -> Self {
+        self.fill_cache = fill;
+        self
+    }
+
+    fn next_item(&mut self, reverse: bool) -> Option<Result<(Vec<u8>, Vec<u8>)>> {
+        let space = self.space;
+
+        while let Some(key) = self.bounds.next_key(&space.index, reverse) {
+            // Locking waits for any change to the item to finish
+            let entry = space.index.lock(&key);
+
+            // The item may have been removed since the key was found
+            if space.expiry.expired(&key) {
+                continue;
+            }
+
+            if let Some(ptr) = entry.value {
+                let cached = if self.fill_cache {
+                    self.cache.get_ref(space.id(), &key)
+                } else {
+                    self.cache.peek(space.id(), &key)
+                };
+
+                if let Some(val) = cached {
+                    return Some(Ok((Vec::from(&*key), val.to_vec())));
+                }
+
+                let (cache, fill, verify) = (self.cache, self.fill_cache, self.verify);
+                let val = self.volume.read(ptr, |strand| {
+                    read_item(strand, ptr, verify, |ctx| {
+                        match cache.fill(fill, space.id(), &ctx)? {
+                            Some(val) => Ok(val.to_vec()),
+                            None => Ok(ctx.val()?.into_owned()),
+                        }
+                    })
+                });
+
+                return Some(val.map(|val| (Vec::from(&*key), val)));
+            }
+        }
+
+        None
+    }
+}
+
+impl<'s> Iterator for Range<'s> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_item(false)
+    }
+}
+
+impl<'s> DoubleEndedIterator for Range<'s> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.next_item(true)
+    }
+}
+
+/// An iterator over a range of keys in the datastore, in key order.
+///
+/// This struct is created by [`Store::keys`] and [`Store::keys_with_prefix`].
+/// Like [`Store::exists`], it only inspects the in-memory index, and never
+/// needs to go to disk. It may also be iterated in reverse.
+///
+/// [`Store::keys`]: struct.Store.html#method.keys
+/// [`Store::keys_with_prefix`]: struct.Store.html#method.keys_with_prefix
+/// [`Store::exists`]: struct.Store.html#method.exists
+#[derive(Debug)]
+pub struct Keys<'s> {
+    index: &'s Index,
+    expiry: &'s Expiry,
+    bounds: Bounds,
+}
+
+impl<'s> Keys<'s> {
+    pub(crate) fn new(index: &'s Index, expiry: &'s Expiry, bounds: Bounds) -> Self {
+        Keys {
+            index: index,
+            expiry: expiry,
+            bounds: bounds,
+        }
+    }
+
+    fn next_key(&mut self, reverse: bool) -> Option<Vec<u8>> {
+        while let Some(key) = self.bounds.next_key(self.index, reverse) {
+            if !self.expiry.expired(&key) {
+                return Some(Vec::from(key));
+            }
+        }
+
+        None
+    }
+}
+
+impl<'s> Iterator for Keys<'s> {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_key(false)
+    }
+}
+
+impl<'s> DoubleEndedIterator for Keys<'s> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.next_key(true)
+    }
+}
@@ -0,0 +1,244 @@
+/*
+ * keyspace.rs
+ *
+ * striking-db - Persistent key/value store for SSDs.
+ * Copyright (c) 2017 Maxwell Duzen, Ammon Smith
+ *
+ * striking-db is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Lesser General Public License as
+ * published by the Free Software Foundation, either version 2 of
+ * the License, or (at your option) any later version.
+ *
+ * striking-db is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public
+ * License along with striking-db.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+
+use expiry::{Expiry, ExpiryMap};
+use index::{Index, IndexTree};
+use parking_lot::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::collections::BTreeMap;
+use std::marker::PhantomData;
+use std::ops::Deref;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::u32;
+use store::Store;
+
+/// A handle to a named keyspace. See [`Store::keyspace`].
+///
+/// It dereferences to a [`Store`], whose methods only see and change the
+/// items in this keyspace. Methods that act on the whole volume, such as
+/// [`Store::vacuum`] and [`Store::checkpoint`], still cover every keyspace.
+/// The handle can't outlive the store it was opened from.
+///
+/// [`Store`]: struct.Store.html
+/// [`Store::keyspace`]: struct.Store.html#method.keyspace
+/// [`Store::vacuum`]: struct.Store.html#method.vacuum
+/// [`Store::checkpoint`]: struct.Store.html#method.checkpoint
+#[derive(Debug)]
+pub struct Keyspace<'s> {
+    store: Store,
+    name: String,
+    phantom: PhantomData<&'s Store>,
+}
+
+impl<'s> Keyspace<'s> {
+    pub(crate) fn new(store: Store, name: &str) -> Self {
+        Keyspace {
+            store: store,
+            name: String::from(name),
+            phantom: PhantomData,
+        }
+    }
+
+    /// The name the keyspace was opened with.
+    #[inline]
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl<'s> Deref for Keyspace<'s> {
+    type Target = Store;
+
+    #[inline]
+    fn deref(&self) -> &Store {
+        &self.store
+    }
+}
+
+// The keyspace used by the store itself, which is not named
+pub const DEFAULT_KEYSPACE: u32 = 0;
+
+// Holds an item for every named keyspace, whose key is the
+// name and whose value is the id, as four little-endian bytes.
+pub const CATALOG: u32 = u32::MAX;
+
+#[derive(Debug)]
+pub struct Space {
+    id: u32,
+    pub index: Index,
+    pub expiry: Expiry,
+    dropped: AtomicBool,
+}
+
+impl Space {
+    fn new(id: u32, index: Index, expiry: Expiry) -> Self {
+        Space {
+            id: id,
+            index: index,
+            expiry: expiry,
+            dropped: AtomicBool::new(false),
+        }
+    }
+
+    #[inline]
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    // Once dropped, entries in the keyspace can no longer be changed.
+    // Must only be set while the index is frozen.
+    #[inline]
+    pub fn dropped(&self) -> bool {
+        self.dropped.load(Ordering::SeqCst)
+    }
+
+    #[inline]
+    pub fn set_dropped(&self) {
+        self.dropped.store(true, Ordering::SeqCst);
+    }
+}
+
+#[derive(Debug)]
+pub struct Registry {
+    spaces: BTreeMap<u32, Arc<Space>>,
+    names: BTreeMap<String, u32>,
+    next_id: u32,
+}
+
+impl Registry {
+    pub fn find(&self, name: &str) -> Option<Arc<Space>> {
+        self.names.get(name).map(|id| self.spaces[id].clone())
+    }
+
+    #[inline]
+    pub fn get(&self, id: u32) -> Option<&Arc<Space>> {
+        self.spaces.get(&id)
+    }
+
+    #[inline]
+    pub fn catalog(&self) -> &Arc<Space> {
+        &self.spaces[&CATALOG]
+    }
+
+    // Every keyspace, including the catalog, in order of id
+    pub fn all(&self) -> Vec<Arc<Space>> {
+        self.spaces.values().cloned().collect()
+    }
+
+    // The id the next keyspace will be given. Ids are never
+    // reused, so stale items can't end up in a new keyspace.
+    #[inline]
+    pub fn next_id(&self) -> u32 {
+        self.next_id
+    }
+
+    pub fn create(&mut self, name: &str) -> Arc<Space> {
+        let id = self.next_id;
+        let space = Arc::new(Space::new(id, Index::new(), Expiry::new()));
+
+        self.next_id += 1;
+        self.spaces.insert(id, space.clone());
+        self.names.insert(String::from(name), id);
+        space
+    }
+
+    pub fn remove(&mut self, name: &str) -> Option<Arc<Space>> {
+        match self.names.remove(name) {
+            Some(id) => self.spaces.remove(&id),
+            None => None,
+        }
+    }
+}
+
+// Every keyspace in the volume. The lock is taken before any
+// entry, and held for writing while keyspaces are added or dropped.
+#[derive(Debug)]
+pub struct Spaces(RwLock<Registry>);
+
+impl Spaces {
+    pub fn new() -> Self {
+        Self::from(BTreeMap::new(), BTreeMap::new(), DEFAULT_KEYSPACE + 1)
+    }
+
+    // The default keyspace and catalog are added if they are missing
+    pub fn from(
+        mut trees: BTreeMap<u32, (IndexTree, ExpiryMap)>,
+        names: BTreeMap<String, u32>,
+        next_id: u32,
+    ) -> Self {
+        let mut spaces = BTreeMap::new();
+
+        for &id in &[DEFAULT_KEYSPACE, CATALOG] {
+            trees.entry(id).or_insert_with(Default::default);
+        }
+
+        for (id, (index, expiry)) in trees {
+            let space = Space::new(id, Index::from(index), Expiry::from(expiry));
+            spaces.insert(id, Arc::new(space));
+        }
+
+        debug_assert!(names.values().all(|id| spaces.contains_key(id)));
+        debug_assert!(spaces.keys().all(|&id| id < next_id || id == CATALOG));
+
+        Spaces(RwLock::new(Registry {
+            spaces: spaces,
+            names: names,
+            next_id: next_id,
+        }))
+    }
+
+    #[inline]
+    pub fn default_space(&self) -> Arc<Space> {
+        self.0.read().spaces[&DEFAULT_KEYSPACE].clone()
+    }
+
+    #[inline]
+    pub fn find(&self, name: &str) -> Option<Arc<Space>> {
+        self.0.read().find(name)
+    }
+
+    #[inline]
+    pub fn get(&self, id: u32) -> Option<Arc<Space>> {
+        self.0.read().get(id).cloned()
+    }
+
+    #[inline]
+    pub fn all(&self) -> Vec<Arc<Space>> {
+        self.0.read().all()
+    }
+
+    #[inline]
+    pub fn read(&self) -> RwLockReadGuard<Registry> {
+        self.0.read()
+    }
+
+    #[inline]
+    pub fn write(&self) -> RwLockWriteGuard<Registry> {
+        self.0.write()
+    }
+}
+
+impl Default for Spaces {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -36,7 +36,6 @@ extern crate cfg_if;
 
 #[macro_use]
 extern crate lazy_static;
-extern crate lru_time_cache;
 extern crate num_cpus;
 extern crate parking_lot;
 
@@ -53,6 +52,10 @@ cfg_if! {
     }
 }
 
+/* Optional dependencies */
+#[cfg(feature = "serde")]
+extern crate serde;
+
 /* Generated sources */
 mod build {
     #![allow(unused)]
@@ -65,28 +68,50 @@ mod serial_capnp {
 }
 
 /* Private fields */
+mod batch;
 mod buffer;
 mod cache;
+mod checkpoint;
 mod deleted;
 mod device;
 mod error;
+mod expiry;
 mod index;
+mod iter;
+mod keyspace;
 mod options;
 mod serial;
+mod snapshot;
 mod stats;
 mod store;
 mod strand;
+mod transaction;
+#[cfg(feature = "serde")]
+mod typed;
 mod utils;
+mod vacuum;
+mod value;
 mod volume;
+mod worker;
 
 type FilePointer = u64;
 
 /* Reexports */
 
+pub use batch::WriteBatch;
+pub use cache::{Uncached, ValueRef};
+pub use device::{Device, Memory, Ssd, check_read, check_trim, check_write};
 pub use error::{Error, Result};
-pub use options::{OpenMode, OpenOptions};
-pub use stats::Stats;
+pub use iter::{KeyRange, Keys, Range};
+pub use keyspace::Keyspace;
+pub use options::{OpenMode, OpenOptions, VacuumOptions};
+pub use snapshot::{Snapshot, SnapshotRange};
+pub use stats::{ReindexReport, Stats};
 pub use store::Store;
+pub use transaction::Transaction;
+#[cfg(feature = "serde")]
+pub use typed::{Codec, Ordered, TypedRange, TypedStore};
+pub use value::ValueReader;
 
 /// The version of this crate, as a string.
 pub const VERSION_STR: &'static str = build::PKG_VERSION;
@@ -98,11 +123,27 @@ pub const MAX_KEY_LEN: usize = 128 * 1024 * 1024; /* 128 KiB */
 /// The maximum size of a valid value (512 MiB).
 pub const MAX_VAL_LEN: usize = 512 * 1024 * 1024 * 1024; /* 512 MiB */
 
+/// The longest value that is read into memory all at once (64 MiB), such
+/// as by [`Store::get`]. Longer values are read in parts, with
+/// [`Store::open_value`] or [`Store::read_range`].
+///
+/// [`Store::get`]: struct.Store.html#method.get
+/// [`Store::open_value`]: struct.Store.html#method.open_value
+/// [`Store::read_range`]: struct.Store.html#method.read_range
+pub const MAX_READ_LEN: usize = 64 * 1024 * 1024; /* 64 MiB */
+
 /// The minimum number of strands that a datastore can be created with.
 pub const MIN_STRANDS: u16 = 2;
 
-const PAGE_SIZE: usize = 4 * 1024;
-const TRIM_SIZE: usize = 256 * 1024;
+/// The unit that every read and write of a [`Device`] is aligned to (4 KiB).
+///
+/// [`Device`]: trait.Device.html
+pub const PAGE_SIZE: usize = 4 * 1024;
+
+/// The unit that every trim of a [`Device`] is aligned to (256 KiB).
+///
+/// [`Device`]: trait.Device.html
+pub const TRIM_SIZE: usize = 256 * 1024;
 
 const PAGE_SIZE64: u64 = PAGE_SIZE as u64;
 const TRIM_SIZE64: u64 = TRIM_SIZE as u64;
@@ -19,6 +19,8 @@
  *
  */
 
+use std::time::Duration;
+
 /// How to open the datastore.
 #[derive(Debug, Copy, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub enum OpenMode {
@@ -39,6 +41,36 @@ impl Default for OpenMode {
     }
 }
 
+/// Settings for the background vacuum thread.
+/// See [`OpenOptions::vacuum`].
+///
+/// [`OpenOptions::vacuum`]: struct.OpenOptions.html#structfield.vacuum
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+pub struct VacuumOptions {
+    /// How many deleted items a strand may have, as a percentage
+    /// of its valid items, before it is vacuumed. For instance,
+    /// `100` means a strand is vacuumed once it has more deleted
+    /// items than valid ones.
+    pub threshold: u32,
+
+    /// How long to wait between checks of each strand.
+    pub interval: Duration,
+
+    /// The maximum number of bytes that may be relocated per second,
+    /// so that vacuuming does not starve other writers.
+    pub io_budget: u64,
+}
+
+impl Default for VacuumOptions {
+    fn default() -> Self {
+        VacuumOptions {
+            threshold: 100,
+            interval: Duration::from_secs(10),
+            io_budget: 16 * 1024 * 1024, /* 16 MiB */
+        }
+    }
+}
+
 /// Specify options when opening a datastore.
 #[derive(Debug, Default, Clone, Hash, PartialEq, Eq)]
 pub struct OpenOptions {
@@ -56,14 +88,45 @@ pub struct OpenOptions {
     /// new datastore.
     pub strands: Option<u16>,
 
-    /// How many items to keep in the item cache.
-    /// If `None`, then use a default value.
+    /// How many bytes of values to keep in the read cache,
+    /// including their keys and some bookkeeping overhead.
+    /// A size of zero disables the cache.
+    /// If `None`, then use a default value (32 MiB).
     pub read_cache: Option<usize>,
 
+    /// The largest value, in bytes, that will be put in the
+    /// read cache. Larger values are always read from disk.
+    /// If `None`, then use a default value (64 KiB).
+    pub read_cache_max_value: Option<usize>,
+
     /// If this is `true`, then ignore the indexer
     /// as written on disk, and instead rebuild it
     /// from the items actually on disk.
+    /// See [`Store::reindex_report`].
+    ///
+    /// [`Store::reindex_report`]: struct.Store.html#method.reindex_report
     pub reindex: bool,
+
+    /// If this is `true`, then the checksums of items
+    /// are not verified when they are read. This makes
+    /// lookups faster, but corrupt values may be returned.
+    ///
+    /// Items are always verified when reindexing or vacuuming.
+    pub skip_checksums: bool,
+
+    /// If set, then a background thread is started
+    /// that vacuums strands with many deleted items.
+    /// See [`VacuumOptions`].
+    ///
+    /// [`VacuumOptions`]: struct.VacuumOptions.html
+    pub vacuum: Option<VacuumOptions>,
+
+    /// If set, then a background thread is started that
+    /// saves the state of the datastore at this interval.
+    /// See [`Store::checkpoint`].
+    ///
+    /// [`Store::checkpoint`]: struct.Store.html#method.checkpoint
+    pub checkpoint: Option<Duration>,
 }
 
 impl OpenOptions {
@@ -97,10 +160,17 @@ impl OpenOptions {
         self
     }
 
-    /// Sets the size of the read cache, and returns `&mut self`
-    /// for chaining methods.
-    pub fn read_cache(&mut self, items: usize) -> &mut Self {
-        self.read_cache = Some(items);
+    /// Sets the size of the read cache in bytes, and returns
+    /// `&mut self` for chaining methods.
+    pub fn read_cache(&mut self, bytes: usize) -> &mut Self {
+        self.read_cache = Some(bytes);
+        self
+    }
+
+    /// Sets the largest value to put in the read cache,
+    /// and returns `&mut self` for chaining methods.
+    pub fn read_cache_max_value(&mut self, bytes: usize) -> &mut Self {
+        self.read_cache_max_value = Some(bytes);
         self
     }
 
@@ -110,4 +180,25 @@ impl OpenOptions {
         self.reindex = true;
         self
     }
+
+    /// Disables checksum verification on reads, and returns
+    /// `&mut self` for chaining methods.
+    pub fn skip_checksums(&mut self) -> &mut Self {
+        self.skip_checksums = true;
+        self
+    }
+
+    /// Enables the background vacuum thread with the given
+    /// settings, and returns `&mut self` for chaining methods.
+    pub fn vacuum(&mut self, vacuum: VacuumOptions) -> &mut Self {
+        self.vacuum = Some(vacuum);
+        self
+    }
+
+    /// Enables periodic checkpoints with the given interval,
+    /// and returns `&mut self` for chaining methods.
+    pub fn checkpoint(&mut self, interval: Duration) -> &mut Self {
+        self.checkpoint = Some(interval);
+        self
+    }
 }
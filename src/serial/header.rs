@@ -96,7 +96,6 @@ impl VolumeHeader {
         Ok(Self::new(strands, state_ptr))
     }
 
-    #[allow(unused)]
     pub fn write(self, page: &mut Page) -> Result<()> {
         let mut slice = &mut page[..];
         serialize_packed::write_message(&mut slice, &*self.0.into_head())?;
@@ -144,7 +143,14 @@ impl fmt::Debug for VolumeHeader {
 pub struct StrandHeader(StrandHeaderRental);
 
 impl StrandHeader {
-    fn _new(id: u16, capacity: u64, offset: u64, stats: &Stats) -> Self {
+    fn _new(
+        id: u16,
+        capacity: u64,
+        offset: u64,
+        generation: u64,
+        sequences: (u64, u64),
+        stats: &Stats,
+    ) -> Self {
         let message = Builder::new(PageAllocator::new());
         let rental = StrandHeaderRental::new(Box::new(message), |message| {
             let mut header = message.init_root::<strand_header::Builder>();
@@ -153,6 +159,11 @@ impl StrandHeader {
             header.set_id(id);
             header.set_capacity(capacity);
             header.set_offset(offset);
+            header.set_generation(generation);
+
+            let (min_sequence, max_sequence) = sequences;
+            header.set_min_sequence(min_sequence);
+            header.set_max_sequence(max_sequence);
 
             header.set_stats_read_bytes(stats.read_bytes);
             header.set_stats_written_bytes(stats.written_bytes);
@@ -169,7 +180,7 @@ impl StrandHeader {
     }
 
     pub fn new(id: u16, capacity: u64) -> Self {
-        Self::_new(id, capacity, PAGE_SIZE64, &Stats::default())
+        Self::_new(id, capacity, PAGE_SIZE64, 0, (0, 0), &Stats::default())
     }
 
     pub fn from(strand: &mut Strand) -> Self {
@@ -177,6 +188,8 @@ impl StrandHeader {
             strand.id(),
             strand.capacity(),
             strand.offset(),
+            strand.generation(),
+            strand.sequences(),
             strand.stats.get_mut(),
         )
     }
@@ -193,6 +206,8 @@ impl StrandHeader {
         let id = header.get_id();
         let capacity = header.get_capacity();
         let offset = header.get_offset();
+        let generation = header.get_generation();
+        let sequences = (header.get_min_sequence(), header.get_max_sequence());
 
         let stats = Stats {
             read_bytes: header.get_stats_read_bytes(),
@@ -202,9 +217,12 @@ impl StrandHeader {
             buffer_written_bytes: header.get_stats_buffer_written_bytes(),
             valid_items: header.get_stats_valid_items(),
             deleted_items: header.get_stats_deleted_items(),
+
+            // The read cache's counters aren't saved
+            ..Stats::default()
         };
 
-        Ok(Self::_new(id, capacity, offset, &stats))
+        Ok(Self::_new(id, capacity, offset, generation, sequences, &stats))
     }
 
     pub fn write(self, page: &mut Page) -> Result<()> {
@@ -231,7 +249,19 @@ impl StrandHeader {
         )
     }
 
-    #[allow(unused)]
+    pub fn get_generation(&self) -> u64 {
+        self.0.rent(
+            |message| message.borrow_as_reader().get_generation(),
+        )
+    }
+
+    pub fn get_sequences(&self) -> (u64, u64) {
+        self.0.rent(|message| {
+            let reader = message.borrow_as_reader();
+            (reader.get_min_sequence(), reader.get_max_sequence())
+        })
+    }
+
     pub fn get_stats(&self) -> Stats {
         self.0.rent(|message| {
             let reader = message.borrow_as_reader();
@@ -244,6 +274,7 @@ impl StrandHeader {
                 buffer_written_bytes: reader.get_stats_buffer_written_bytes(),
                 valid_items: reader.get_stats_valid_items(),
                 deleted_items: reader.get_stats_deleted_items(),
+                ..Stats::default()
             }
         })
     }
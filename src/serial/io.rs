@@ -59,6 +59,10 @@ impl<'s, 'd> StrandReader<'s, 'd> {
         }
     }
 
+    pub fn get_pointer(&self) -> FilePointer {
+        self.cursor + self.strand.start()
+    }
+
     // Ignores the cursor. It is up to the caller to ensure
     // that the cursor matches with the current buffer.
     fn read_page(&mut self, offset: u64) -> io::Result<()> {
@@ -151,6 +155,17 @@ impl<'s, 'd> StrandWriter<'s, 'd> {
         }
     }
 
+    // Writes past the strand's offset without moving it, for data
+    // that isn't part of the strand until the offset is pushed past it
+    pub fn staged(strand: &'s mut Strand<'d>, offset: u64) -> Self {
+        StrandWriter {
+            strand: strand,
+            buffer: Box::new(Buffer::new()),
+            cursor: offset,
+            update_offset: false,
+        }
+    }
+
     pub fn get_pointer(&self) -> FilePointer {
         self.cursor + self.strand.start()
     }
@@ -166,7 +181,7 @@ impl<'s, 'd> StrandWriter<'s, 'd> {
 
 impl<'s, 'd> Write for StrandWriter<'s, 'd> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        if buf.len() > self.strand.remaining() as usize {
+        if buf.len() as u64 > self.strand.capacity() - self.cursor {
             return Err(io::Error::new(
                 io::ErrorKind::UnexpectedEof,
                 "Out of disk space",
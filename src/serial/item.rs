@@ -19,28 +19,137 @@
  *
  */
 
-use super::{FilePointer, Result, StrandReader, StrandWriter};
+use super::{MAX_READ_LEN, FilePointer, Result, StrandReader, StrandWriter};
+use super::checksum::Crc32c;
 use super::serial_capnp::item;
 use super::strand::Strand;
+use super::volume::Volume;
 use capnp::message::{Builder, ReaderOptions};
 use capnp::serialize_packed;
+use error::Error;
+use std::borrow::Cow;
 use std::cmp::min;
-use std::io::Write;
+use std::io::{self, Read, Write};
+
+// Unpacked values are stored in chunks of this size, each followed by
+// its own checksum, so any part of one can be read and verified without
+// reading the rest. This is also how much of one is buffered at a time.
+const CHUNK_SIZE: usize = 64 * 1024;
+
+// Values at least this long are stored unpacked after their item,
+// like streamed ones, so that part of them can be read without
+// decoding the rest.
+const BLOB_THRESHOLD: usize = 4096;
+
+// Follows the chunks of an unpacked value: the item's sequence number,
+// then a checksum of it and the item's own checksum. Streamed values
+// are only given a sequence number once all of their bytes are written.
+const TRAILER_LEN: u64 = 12;
 
 #[derive(Clone)]
-pub struct ReadContext<'a>(item::Reader<'a>);
+pub struct ReadContext<'a> {
+    item: item::Reader<'a>,
+    strand: &'a Strand<'a>,
+    ptr: FilePointer,
+    blob: Option<FilePointer>,
+    sequence: u64,
+    verify: bool,
+}
 
 impl<'a> ReadContext<'a> {
     #[inline]
     pub fn key(&self) -> Result<&[u8]> {
-        let slice = self.0.get_key()?;
+        let slice = self.item.get_key()?;
         Ok(slice)
     }
 
+    // Unpacked values are read from disk here, unless
+    // they're too long to be read into memory at once
+    pub fn val(&self) -> Result<Cow<[u8]>> {
+        match self.blob() {
+            Some((_, len)) if len > MAX_READ_LEN as u64 => Err(Error::ValueTooLarge),
+            Some((ptr, len)) => {
+                let mut buffer = vec![0; len as usize];
+                let mut reader = BlobReader::new(self.strand, self.ptr, ptr, len, self.verify);
+                reader.read_exact(&mut buffer)?;
+                Ok(Cow::Owned(buffer))
+            }
+            None => {
+                let slice = self.item.get_value()?;
+                Ok(Cow::Borrowed(slice))
+            }
+        }
+    }
+
+    // Compares the value a chunk at a time, so that
+    // an unpacked one is never all in memory at once
+    pub fn val_eq(&self, other: &[u8]) -> Result<bool> {
+        let (ptr, len) = match self.blob() {
+            Some(blob) => blob,
+            None => return Ok(self.item.get_value()? == other),
+        };
+
+        if len != other.len() as u64 {
+            return Ok(false);
+        }
+
+        let mut cursor = BlobCursor::new(self.ptr, ptr, len, self.verify);
+        let mut buffer = vec![0; min(len, CHUNK_SIZE as u64) as usize];
+        let mut pos = 0;
+
+        while pos < other.len() {
+            let read = cursor.read(self.strand, &mut buffer)?;
+            if buffer[..read] != other[pos..pos + read] {
+                return Ok(false);
+            }
+
+            pos += read;
+        }
+
+        Ok(true)
+    }
+
+    // The length of the value, without reading it from disk
+    pub fn val_len(&self) -> Result<usize> {
+        match self.blob() {
+            Some((_, len)) => Ok(len as usize),
+            None => Ok(self.item.get_value()?.len()),
+        }
+    }
+
     #[inline]
-    pub fn val(&self) -> Result<&[u8]> {
-        let slice = self.0.get_value()?;
-        Ok(slice)
+    pub fn keyspace(&self) -> u32 {
+        self.item.get_keyspace()
+    }
+
+    #[inline]
+    pub fn sequence(&self) -> u64 {
+        self.sequence
+    }
+
+    #[inline]
+    pub fn tombstone(&self) -> bool {
+        self.item.get_tombstone()
+    }
+
+    #[inline]
+    pub fn expires(&self) -> Option<u64> {
+        match self.item.get_expires() {
+            0 => None,
+            time => Some(time),
+        }
+    }
+
+    // Where the raw bytes of a streamed value start, and how many there are
+    #[inline]
+    pub fn blob(&self) -> Option<(FilePointer, u64)> {
+        self.blob.map(|ptr| (ptr, self.item.get_blob_len()))
+    }
+
+    // Whether this is a saved DatastoreState rather than an item
+    #[inline]
+    pub fn is_state(&self) -> bool {
+        self.item.has_state()
     }
 
     fn copy_slice(slice: &[u8], buffer: &mut [u8]) -> usize {
@@ -56,45 +165,776 @@ impl<'a> ReadContext<'a> {
     #[inline]
     #[allow(unused)]
     pub fn copy_key(&self, key_buf: &mut [u8]) -> Result<usize> {
-        let slice = self.0.get_key()?;
+        let slice = self.item.get_key()?;
         Ok(Self::copy_slice(slice, key_buf))
     }
 
     #[inline]
     pub fn copy_val(&self, val_buf: &mut [u8]) -> Result<usize> {
-        let slice = self.0.get_value()?;
-        Ok(Self::copy_slice(slice, val_buf))
+        self.copy_val_range(0, val_buf)
+    }
+
+    // Only the chunks of an unpacked value that hold
+    // the copied bytes are read, and each is verified.
+    pub fn copy_val_range(&self, offset: u64, val_buf: &mut [u8]) -> Result<usize> {
+        match self.blob() {
+            Some((ptr, len)) => {
+                let mut cursor = BlobCursor::new(self.ptr, ptr, len, self.verify);
+                cursor.seek(offset);
+
+                let mut copied = 0;
+                while copied < val_buf.len() {
+                    match cursor.read(self.strand, &mut val_buf[copied..])? {
+                        0 => break,
+                        len => copied += len,
+                    }
+                }
+
+                Ok(copied)
+            }
+            None => {
+                let slice = self.item.get_value()?;
+                let offset = min(offset, slice.len() as u64) as usize;
+                Ok(Self::copy_slice(&slice[offset..], val_buf))
+            }
+        }
+    }
+}
+
+fn checksum(key: &[u8], val: &[u8], sequence: u64, tombstone: bool, extra: [u64; 3]) -> u32 {
+    let mut bytes = [0; 33];
+    let mut len = 9;
+    for i in 0..8 {
+        bytes[i] = (sequence >> (i * 8)) as u8;
+    }
+    bytes[8] = tombstone as u8;
+
+    // The expiry, unpacked length and keyspace are only summed if set,
+    // so items without them are summed as they were before they existed
+    for &field in extra.iter().filter(|&&field| field != 0) {
+        for i in 0..8 {
+            bytes[len + i] = (field >> (i * 8)) as u8;
+        }
+
+        len += 8;
+    }
+
+    let mut crc = Crc32c::new();
+    crc.update(key);
+    crc.update(val);
+    crc.update(&bytes[..len]);
+    crc.finish()
+}
+
+fn verify_item(ptr: FilePointer, item: &item::Reader) -> Result<()> {
+    // Checkpoints are not checksummed
+    if item.has_state() {
+        return Ok(());
+    }
+
+    let key = item.get_key()?;
+    let val = item.get_value()?;
+    let expected = checksum(
+        key,
+        val,
+        item.get_sequence(),
+        item.get_tombstone(),
+        [
+            item.get_expires(),
+            item.get_blob_len(),
+            item.get_keyspace() as u64,
+        ],
+    );
+
+    if item.get_checksum() == expected {
+        Ok(())
+    } else {
+        Err(Error::CorruptItem(ptr))
     }
 }
 
-pub fn read_item<F, R>(strand: &Strand, ptr: FilePointer, func: F) -> Result<R>
+// Finds the raw bytes of a value that was streamed in, which directly
+// follow the item they belong to, and the sequence number after them.
+fn find_blob(
+    strand: &Strand,
+    ptr: FilePointer,
+    item: &item::Reader,
+    verify: bool,
+) -> Result<Option<(FilePointer, u64)>> {
+    let len = item.get_blob_len();
+    if len == 0 {
+        return Ok(None);
+    }
+
+    // The chunks and the trailer must be within the strand's data
+    let end = strand.start() + strand.offset();
+    let blob_end = match blob_disk_len(len).and_then(|disk_len| ptr.checked_add(disk_len)) {
+        Some(blob_end) if blob_end <= end => blob_end,
+        _ => return Err(Error::Corrupt),
+    };
+
+    let mut bytes = [0; TRAILER_LEN as usize];
+    let mut strand_reader = StrandReader::new(strand, blob_end - TRAILER_LEN);
+    strand_reader.read_exact(&mut bytes)?;
+
+    let sum = [bytes[8], bytes[9], bytes[10], bytes[11]];
+    if verify && checksum_from_bytes(sum) != trailer_checksum(item.get_checksum(), &bytes[..8]) {
+        return Err(Error::Corrupt);
+    }
+
+    let mut sequence = 0;
+    for i in 0..8 {
+        sequence |= (bytes[i] as u64) << (i * 8);
+    }
+
+    Ok(Some((ptr, sequence)))
+}
+
+// How many bytes follow the item of an unpacked value of the given
+// length, including the checksum of each chunk and the trailer
+fn blob_disk_len(len: u64) -> Option<u64> {
+    let chunk_size = CHUNK_SIZE as u64;
+    let chunks = len / chunk_size + (len % chunk_size != 0) as u64;
+    chunks
+        .checked_mul(4)
+        .and_then(|sums| sums.checked_add(len))
+        .and_then(|disk_len| disk_len.checked_add(TRAILER_LEN))
+}
+
+// The trailer is tied to its item, so that one left
+// over from an earlier write to the strand isn't used
+fn blob_trailer(item_sum: u32, sequence: u64) -> [u8; TRAILER_LEN as usize] {
+    let mut bytes = [0; TRAILER_LEN as usize];
+    for i in 0..8 {
+        bytes[i] = (sequence >> (i * 8)) as u8;
+    }
+
+    let sum = checksum_bytes(trailer_checksum(item_sum, &bytes[..8]));
+    bytes[8..].copy_from_slice(&sum);
+    bytes
+}
+
+fn trailer_checksum(item_sum: u32, sequence: &[u8]) -> u32 {
+    let mut crc = Crc32c::new();
+    crc.update(&checksum_bytes(item_sum));
+    crc.update(sequence);
+    crc.finish()
+}
+
+// Each chunk's index is summed along with it, so
+// that chunks which are out of place are caught
+fn chunk_checksum(index: u64, chunk: &[u8]) -> u32 {
+    let mut bytes = [0; 8];
+    for i in 0..8 {
+        bytes[i] = (index >> (i * 8)) as u8;
+    }
+
+    let mut crc = Crc32c::new();
+    crc.update(&bytes);
+    crc.update(chunk);
+    crc.finish()
+}
+
+// A position in an unpacked value, from which it is read a chunk at a
+// time. Each chunk is checked against its checksum when it is loaded,
+// if verifying, so none of the bytes handed out are unverified. The
+// strand is passed in on every read, so that it needn't stay locked.
+#[derive(Debug)]
+pub struct BlobCursor {
+    item_ptr: FilePointer,
+    ptr: FilePointer,
+    len: u64,
+    pos: u64,
+    verify: bool,
+
+    // The chunk holding the position, once it has been loaded
+    chunk: Vec<u8>,
+    loaded: Option<u64>,
+}
+
+impl BlobCursor {
+    pub fn new(item_ptr: FilePointer, ptr: FilePointer, len: u64, verify: bool) -> Self {
+        BlobCursor {
+            item_ptr: item_ptr,
+            ptr: ptr,
+            len: len,
+            pos: 0,
+            verify: verify,
+            chunk: Vec::new(),
+            loaded: None,
+        }
+    }
+
+    // Where the value starts, which is in the strand to be passed to read()
+    #[inline]
+    pub fn ptr(&self) -> FilePointer {
+        self.ptr
+    }
+
+    #[inline]
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    #[inline]
+    pub fn pos(&self) -> u64 {
+        self.pos
+    }
+
+    #[inline]
+    pub fn seek(&mut self, pos: u64) {
+        self.pos = pos;
+    }
+
+    // Copies what it can from the chunk holding the position,
+    // returning 0 once the end of the value has been reached
+    pub fn read(&mut self, strand: &Strand, buf: &mut [u8]) -> Result<usize> {
+        if self.pos >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let index = self.pos / CHUNK_SIZE as u64;
+        if self.loaded != Some(index) {
+            self.load(strand, index)?;
+        }
+
+        let off = (self.pos % CHUNK_SIZE as u64) as usize;
+        let len = min(self.chunk.len() - off, buf.len());
+        buf[..len].copy_from_slice(&self.chunk[off..off + len]);
+        self.pos += len as u64;
+
+        Ok(len)
+    }
+
+    fn load(&mut self, strand: &Strand, index: u64) -> Result<()> {
+        let start = index * CHUNK_SIZE as u64;
+        let size = min(self.len - start, CHUNK_SIZE as u64) as usize;
+
+        // Forget the old chunk first, in case this one can't be read
+        self.loaded = None;
+        self.chunk.resize(size, 0);
+
+        let mut bytes = [0; 4];
+        let mut strand_reader = StrandReader::new(strand, self.ptr + start + index * 4);
+        strand_reader.read_exact(&mut self.chunk)?;
+        strand_reader.read_exact(&mut bytes)?;
+
+        if self.verify && checksum_from_bytes(bytes) != chunk_checksum(index, &self.chunk) {
+            return Err(Error::CorruptItem(self.item_ptr));
+        }
+
+        self.loaded = Some(index);
+        Ok(())
+    }
+}
+
+// Reads an unpacked value from start to end, from a strand
+// that stays borrowed for as long as the reader exists.
+pub struct BlobReader<'s, 'd: 's> {
+    strand: &'s Strand<'d>,
+    cursor: BlobCursor,
+}
+
+impl<'s, 'd> BlobReader<'s, 'd> {
+    pub fn new(
+        strand: &'s Strand<'d>,
+        item_ptr: FilePointer,
+        ptr: FilePointer,
+        len: u64,
+        verify: bool,
+    ) -> Self {
+        BlobReader {
+            strand: strand,
+            cursor: BlobCursor::new(item_ptr, ptr, len, verify),
+        }
+    }
+}
+
+impl<'s, 'd> Read for BlobReader<'s, 'd> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.cursor.read(self.strand, buf)?;
+        Ok(len)
+    }
+}
+
+// Reads the item at the given pointer, verifying its checksum if
+// requested. Any corruption is reported with the item's location.
+pub fn read_item<F, R>(strand: &Strand, ptr: FilePointer, verify: bool, func: F) -> Result<R>
 where
     F: FnOnce(ReadContext) -> Result<R>,
 {
     let mut strand_reader = StrandReader::new(strand, ptr);
-    let msg_reader = serialize_packed::read_message(&mut strand_reader, ReaderOptions::new())?;
-    let item = msg_reader.get_root::<item::Reader>()?;
-    let ctx = ReadContext(item);
+    let result = serialize_packed::read_message(&mut strand_reader, ReaderOptions::new())
+        .map_err(Error::from)
+        .and_then(|msg_reader| {
+            let item = msg_reader.get_root::<item::Reader>()?;
+            if verify {
+                verify_item(ptr, &item)?;
+            }
+
+            let blob = find_blob(strand, strand_reader.get_pointer(), &item, verify)?;
+            let sequence = blob.map_or(item.get_sequence(), |(_, sequence)| sequence);
+            func(ReadContext {
+                item: item,
+                strand: strand,
+                ptr: ptr,
+                blob: blob.map(|(blob_ptr, _)| blob_ptr),
+                sequence: sequence,
+                verify: verify,
+            })
+        });
 
-    // Run callback and return
-    Ok(func(ctx)?)
+    // Point to the item if it couldn't be decoded
+    match result {
+        Err(Error::Corrupt) => Err(Error::CorruptItem(ptr)),
+        result => result,
+    }
 }
 
-pub fn write_item(strand: &mut Strand, key: &[u8], val: &[u8]) -> Result<FilePointer> {
-    let mut message = Builder::new_default();
+// Reads every item from the given offset in the strand up to the end
+// of its data, stopping at the first record that cannot be decoded
+// or fails its checksum. Returns the pointer where reading stopped.
+pub fn scan_items<F>(strand: &Strand, from: u64, mut func: F) -> FilePointer
+where
+    F: FnMut(FilePointer, ReadContext) -> Result<()>,
+{
+    let end = strand.start() + strand.offset();
+    let mut ptr = strand.start() + from;
+
+    while ptr < end {
+        let mut strand_reader = StrandReader::new(strand, ptr);
+        let result = serialize_packed::read_message(&mut strand_reader, ReaderOptions::new())
+            .map_err(Error::from)
+            .and_then(|msg_reader| {
+                let item = msg_reader.get_root::<item::Reader>()?;
+                verify_item(ptr, &item)?;
+
+                // Every chunk of an unpacked value is checked
+                let blob = find_blob(strand, strand_reader.get_pointer(), &item, true)?;
+                let next = match blob {
+                    Some((blob_ptr, _)) => {
+                        let len = item.get_blob_len();
+                        let mut reader = BlobReader::new(strand, ptr, blob_ptr, len, true);
+                        io::copy(&mut reader, &mut io::sink())?;
+                        blob_ptr + blob_disk_len(len).expect("Blob length was checked")
+                    }
+                    None => strand_reader.get_pointer(),
+                };
+
+                let sequence = blob.map_or(item.get_sequence(), |(_, sequence)| sequence);
+                let ctx = ReadContext {
+                    item: item,
+                    strand: strand,
+                    ptr: ptr,
+                    blob: blob.map(|(blob_ptr, _)| blob_ptr),
+                    sequence: sequence,
+                    verify: true,
+                };
+
+                func(ptr, ctx).map(|_| next)
+            });
+
+        match result {
+            Ok(next) => ptr = next,
+            Err(_) => break,
+        }
+    }
+
+    ptr
+}
+
+#[inline]
+pub fn write_item(
+    strand: &mut Strand,
+    keyspace: u32,
+    key: &[u8],
+    val: &[u8],
+) -> Result<FilePointer> {
+    let sequence = strand.next_sequence();
+    write_value(strand, keyspace, key, val, sequence, None)
+}
+
+#[inline]
+pub fn write_expiring_item(
+    strand: &mut Strand,
+    keyspace: u32,
+    key: &[u8],
+    val: &[u8],
+    expires: u64,
+) -> Result<FilePointer> {
+    let sequence = strand.next_sequence();
+    write_value(strand, keyspace, key, val, sequence, Some(expires))
+}
+
+fn write_value(
+    strand: &mut Strand,
+    keyspace: u32,
+    key: &[u8],
+    val: &[u8],
+    sequence: u64,
+    expires: Option<u64>,
+) -> Result<FilePointer> {
+    if val.len() < BLOB_THRESHOLD {
+        write_record(strand, keyspace, key, Some(val), sequence, expires)
+    } else {
+        let mut reader = val;
+        let len = val.len() as u64;
+        write_blob(strand, keyspace, key, &mut reader, len, sequence, expires)
+    }
+}
+
+// Like write_blob(), but the strand is only locked while each chunk
+// is written, not while the reader waits on its source. That may be
+// slow, or may itself be reading from the volume. The value is written
+// past the end of a reserved strand, which only grows to include it
+// once all of it is on disk. Only then is lock() called, and the item
+// is given its sequence number while what it returns is held, so that
+// the item is newer than any write that was made in the meantime.
+pub fn write_stream<F, G>(
+    volume: &Volume,
+    keyspace: u32,
+    key: &[u8],
+    reader: &mut Read,
+    len: u64,
+    lock: F,
+) -> Result<(FilePointer, G)>
+where
+    F: FnOnce() -> Result<G>,
+{
+    // An empty blob couldn't be told apart from an ordinary item
+    if len == 0 {
+        let guard = lock()?;
+        let ptr = volume.write(|strand| {
+            let sequence = strand.next_sequence();
+            write_record(strand, keyspace, key, Some(&[]), sequence, None)
+        })?;
+
+        return Ok((ptr, guard));
+    }
+
+    volume.reserve(|id| {
+        let (offset, remaining) = volume.strand(id, |strand| (strand.offset(), strand.remaining()));
+
+        let (header, sum) = blob_header(keyspace, key, len, None)?;
+        if blob_size(&header, len) > remaining {
+            return Err(Error::OutOfSpace);
+        }
+
+        // The strand's offset hasn't moved, so there's nothing to undo if this fails
+        let end = stream_blob_bytes(volume, id, offset, &header, reader, len)?;
+
+        let guard = lock()?;
+        let ptr = volume.strand_mut(id, |strand| {
+            let sequence = strand.next_sequence();
+            append(strand, end, &[&blob_trailer(sum, sequence)])?;
+
+            // The header only points past the item once all of it is on disk
+            strand.push_offset(end + TRAILER_LEN - offset);
+            strand.add_sequence(sequence);
+            let result = strand.write_metadata();
+
+            rewind_on_error(strand, offset, result)?;
+            strand.stats.get_mut().valid_items += 1;
+            Ok(strand.start() + offset)
+        })?;
+
+        Ok((ptr, guard))
+    })
+}
+
+#[inline]
+pub fn write_tombstone(strand: &mut Strand, keyspace: u32, key: &[u8]) -> Result<FilePointer> {
+    let sequence = strand.next_sequence();
+    write_record(strand, keyspace, key, None, sequence, None)
+}
+
+// Writes an item with the given sequence number. If val is None,
+// then a tombstone is written to record the key's removal.
+pub fn write_record(
+    strand: &mut Strand,
+    keyspace: u32,
+    key: &[u8],
+    val: Option<&[u8]>,
+    sequence: u64,
+    expires: Option<u64>,
+) -> Result<FilePointer> {
+    let record = Record {
+        keyspace: keyspace,
+        key: key,
+        val: val,
+        sequence: sequence,
+        expires: expires,
+    };
+
+    let ptrs = write_records(strand, &[record])?;
+    Ok(ptrs[0])
+}
+
+#[derive(Debug, Copy, Clone)]
+pub struct Record<'a> {
+    pub keyspace: u32,
+    pub key: &'a [u8],
+    pub val: Option<&'a [u8]>,
+    pub sequence: u64,
+    pub expires: Option<u64>,
+}
+
+// Writes the records back to back, and only then updates the strand
+// header to point past them. Until that point none of them will be
+// found when the volume is opened, so they are all written or none are.
+pub fn write_records(strand: &mut Strand, records: &[Record]) -> Result<Vec<FilePointer>> {
+    // Encode everything first, so a batch that
+    // doesn't fit is never partially written
+    let mut bytes = Vec::new();
+    let mut offsets = Vec::with_capacity(records.len());
+
+    for record in records {
+        let mut message = Builder::new_default();
+        {
+            let mut item = message.init_root::<item::Builder>();
+            item.set_keyspace(record.keyspace);
+            item.set_key(record.key);
+            item.set_sequence(record.sequence);
+
+            match record.val {
+                Some(val) => item.set_value(val),
+                None => item.set_tombstone(true),
+            }
+
+            let expires = record.expires.unwrap_or(0);
+            item.set_expires(expires);
+
+            let val = record.val.unwrap_or(&[]);
+            let tombstone = record.val.is_none();
+            let extra = [expires, 0, record.keyspace as u64];
+            let sum = checksum(record.key, val, record.sequence, tombstone, extra);
+            item.set_checksum(sum);
+        }
+
+        offsets.push(bytes.len() as u64);
+        serialize_packed::write_message(&mut bytes, &message)?;
+    }
+
+    if bytes.len() as u64 > strand.remaining() {
+        return Err(Error::OutOfSpace);
+    }
+
+    for record in records {
+        strand.add_sequence(record.sequence);
+    }
+
+    // Write data
+    let offset = strand.offset();
+    let result = {
+        let mut strand_writer = StrandWriter::new(strand);
+        let ptr = strand_writer.get_pointer();
+
+        write_bytes(&mut strand_writer, &bytes).map(|_| ptr)
+    };
+
+    let ptr = rewind_on_error(strand, offset, result.map_err(Error::from))?;
+
+    // Tombstones are garbage as soon as they are written
     {
-        let mut item = message.init_root::<item::Builder>();
-        item.set_key(key);
-        item.set_value(val);
+        let stats = strand.stats.get_mut();
+        for record in records {
+            match record.val {
+                Some(_) => stats.valid_items += 1,
+                None => stats.deleted_items += 1,
+            }
+        }
     }
 
+    Ok(offsets.iter().map(|off| ptr + off).collect())
+}
+
+// Moves the strand back to where a failed write started,
+// so that the next write replaces whatever made it to disk
+fn rewind_on_error<T>(strand: &mut Strand, offset: u64, result: Result<T>) -> Result<T> {
+    if result.is_err() {
+        strand.rewind(offset);
+    }
+
+    result
+}
+
+fn write_bytes(strand_writer: &mut StrandWriter, bytes: &[u8]) -> io::Result<()> {
+    strand_writer.write_all(bytes)?;
+
+    // The items must be on disk before the header points past them
+    strand_writer.flush()?;
+    strand_writer.write_metadata()
+}
+
+// Writes an item whose value is read from the given reader, instead of
+// being part of the message. The raw bytes are written directly after
+// the item in checksummed chunks, then the trailer, so that the memory
+// used does not depend on the length of the value. Exactly len bytes
+// are read.
+pub fn write_blob(
+    strand: &mut Strand,
+    keyspace: u32,
+    key: &[u8],
+    reader: &mut Read,
+    len: u64,
+    sequence: u64,
+    expires: Option<u64>,
+) -> Result<FilePointer> {
+    // An empty blob couldn't be told apart from an ordinary item
+    if len == 0 {
+        return write_record(strand, keyspace, key, Some(&[]), sequence, expires);
+    }
+
+    let (bytes, sum) = blob_header(keyspace, key, len, expires)?;
+    if blob_size(&bytes, len) > strand.remaining() {
+        return Err(Error::OutOfSpace);
+    }
+
+    strand.add_sequence(sequence);
+
     // Write data
-    let mut strand_writer = StrandWriter::new(strand);
-    let ptr = strand_writer.get_pointer();
+    let offset = strand.offset();
+    let result = {
+        let mut strand_writer = StrandWriter::new(strand);
+        let ptr = strand_writer.get_pointer();
+
+        let trailer = blob_trailer(sum, sequence);
+        write_blob_bytes(&mut strand_writer, &bytes, reader, len, &trailer).map(|_| ptr)
+    };
 
-    serialize_packed::write_message(&mut strand_writer, &message)?;
+    let ptr = rewind_on_error(strand, offset, result)?;
+
+    strand.stats.get_mut().valid_items += 1;
+    Ok(ptr)
+}
+
+fn write_blob_bytes(
+    strand_writer: &mut StrandWriter,
+    header: &[u8],
+    reader: &mut Read,
+    len: u64,
+    trailer: &[u8],
+) -> Result<()> {
+    strand_writer.write_all(header)?;
+    for_each_chunk(reader, len, |chunk, sum| {
+        strand_writer.write_all(chunk)?;
+        strand_writer.write_all(&sum)?;
+        Ok(())
+    })?;
+    strand_writer.write_all(trailer)?;
+
+    // The value must be on disk before the header points past it
+    strand_writer.flush()?;
     strand_writer.write_metadata()?;
+    Ok(())
+}
+
+// Writes the same bytes as write_blob_bytes(), up to the trailer, from
+// the given offset in the reserved strand, locking it for each chunk.
+// The strand's offset and header are left as they were. Returns the
+// offset where the trailer goes.
+fn stream_blob_bytes(
+    volume: &Volume,
+    id: u16,
+    offset: u64,
+    header: &[u8],
+    reader: &mut Read,
+    len: u64,
+) -> Result<u64> {
+    let mut end = volume.strand_mut(id, |strand| append(strand, offset, &[header]))?;
+    for_each_chunk(reader, len, |chunk, sum| {
+        let next = volume.strand_mut(id, |strand| append(strand, end, &[chunk, &sum]))?;
+        end = next;
+        Ok(())
+    })?;
+
+    Ok(end)
+}
+
+// Writes past the strand's offset, returning where the written bytes end
+fn append(strand: &mut Strand, offset: u64, parts: &[&[u8]]) -> Result<u64> {
+    let mut strand_writer = StrandWriter::staged(strand, offset);
+    let mut end = offset;
+    for part in parts {
+        strand_writer.write_all(part)?;
+        end += part.len() as u64;
+    }
+
     strand_writer.flush()?;
+    Ok(end)
+}
 
-    Ok(ptr)
+// Reads exactly len bytes, a chunk at a time, and passes each
+// chunk to the closure along with the checksum to write after it
+fn for_each_chunk<F>(reader: &mut Read, len: u64, mut func: F) -> Result<()>
+where
+    F: FnMut(&[u8], [u8; 4]) -> Result<()>,
+{
+    let mut buffer = vec![0; min(len, CHUNK_SIZE as u64) as usize];
+    let mut index = 0;
+    let mut left = len;
+
+    while left > 0 {
+        let amount = min(left, buffer.len() as u64) as usize;
+        reader.read_exact(&mut buffer[..amount])?;
+
+        let chunk = &buffer[..amount];
+        func(chunk, checksum_bytes(chunk_checksum(index, chunk)))?;
+
+        index += 1;
+        left -= amount as u64;
+    }
+
+    Ok(())
+}
+
+// The item written before a blob's bytes, with their length in place of a
+// value, and its checksum. The sequence number goes in the trailer instead.
+fn blob_header(
+    keyspace: u32,
+    key: &[u8],
+    len: u64,
+    expires: Option<u64>,
+) -> Result<(Vec<u8>, u32)> {
+    let expires = expires.unwrap_or(0);
+    let extra = [expires, len, keyspace as u64];
+    let sum = checksum(key, &[], 0, false, extra);
+
+    let mut message = Builder::new_default();
+    {
+        let mut item = message.init_root::<item::Builder>();
+        item.set_keyspace(keyspace);
+        item.set_key(key);
+        item.set_expires(expires);
+        item.set_blob_len(len);
+        item.set_checksum(sum);
+    }
+
+    let mut bytes = Vec::new();
+    serialize_packed::write_message(&mut bytes, &message)?;
+    Ok((bytes, sum))
+}
+
+#[inline]
+fn blob_size(header: &[u8], len: u64) -> u64 {
+    let disk_len = blob_disk_len(len).unwrap_or(u64::max_value());
+    (header.len() as u64).saturating_add(disk_len)
+}
+
+fn checksum_bytes(sum: u32) -> [u8; 4] {
+    let mut bytes = [0; 4];
+    for i in 0..4 {
+        bytes[i] = (sum >> (i * 8)) as u8;
+    }
+
+    bytes
+}
+
+fn checksum_from_bytes(bytes: [u8; 4]) -> u32 {
+    let mut sum = 0;
+    for i in 0..4 {
+        sum |= (bytes[i] as u32) << (i * 8);
+    }
+
+    sum
 }
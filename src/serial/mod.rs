@@ -20,6 +20,7 @@
  */
 
 mod alloc;
+mod checksum;
 mod header;
 mod io;
 mod item;
@@ -28,6 +29,8 @@ mod state;
 
 pub use self::header::{StrandHeader, VolumeHeader};
 pub use self::io::{StrandReader, StrandWriter};
-pub use self::item::{ReadContext, read_item, write_item};
-pub use self::state::DatastoreState;
+pub use self::item::{BlobCursor, BlobReader, ReadContext, Record, read_item, scan_items,
+                     write_blob, write_expiring_item, write_item, write_record, write_records,
+                     write_stream, write_tombstone};
+pub use self::state::{DatastoreState, SavedState, StrandPosition};
 use super::*;
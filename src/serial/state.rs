@@ -21,14 +21,18 @@
 
 use self::rentals::DatastoreStateRental;
 use super::{FilePointer, Result, StrandReader, StrandWriter};
-use super::deleted::{Deleted, DeletedSet};
-use super::index::{Index, IndexTree};
-use super::volume::VolumeState;
+use super::deleted::DeletedSet;
+use super::expiry::ExpiryMap;
+use super::index::IndexTree;
+use super::keyspace::DEFAULT_KEYSPACE;
+use capnp::{data, struct_list};
 use capnp::message::{Builder, HeapAllocator, ReaderOptions};
 use capnp::serialize_packed;
 use error::Error;
-use serial_capnp::{self, datastore_state};
+use serial_capnp::{self, datastore_state, expiring, file_pointer2, item, map};
+use std::collections::BTreeMap;
 use std::fmt;
+use std::io::Write;
 use strand::Strand;
 
 rental! {
@@ -43,26 +47,64 @@ rental! {
     }
 }
 
+// The contents of a DatastoreState read from disk
+#[derive(Debug)]
+pub struct SavedState {
+    pub spaces: BTreeMap<u32, (IndexTree, ExpiryMap)>,
+    pub deleted: DeletedSet,
+    pub next_keyspace: u32,
+    pub sequence: u64,
+    pub positions: Vec<StrandPosition>,
+}
+
+// How far a strand had been written when the state was saved
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct StrandPosition {
+    pub generation: u64,
+    pub offset: u64,
+}
+
 pub struct DatastoreState(DatastoreStateRental);
 
 impl DatastoreState {
-    pub fn new(index: &IndexTree, deleted: &DeletedSet) -> Result<Self> {
+    // Each keyspace is given as its id, index and expiring keys
+    pub fn new(
+        spaces: &[(u32, &IndexTree, &ExpiryMap)],
+        deleted: &DeletedSet,
+        next_keyspace: u32,
+        sequence: u64,
+        positions: &[StrandPosition],
+    ) -> Result<Self> {
         use rental::TryNewError;
 
         let message = Builder::new_default();
         let try_rental = DatastoreStateRental::try_new(Box::new(message), |message| {
-            let mut state = message.init_root::<datastore_state::Builder>();
+            // Stored as an item so that it can be written among them
+            let item = message.init_root::<item::Builder>();
+            let mut state = item.init_state();
 
             state.set_signature(serial_capnp::STATE_MAGIC);
 
+            // The default keyspace is kept where older versions expect it
+            let others = spaces
+                .iter()
+                .filter(|&&(id, _, _)| id != DEFAULT_KEYSPACE)
+                .collect::<Vec<_>>();
+
+            let default = spaces.iter().find(|&&(id, _, _)| id == DEFAULT_KEYSPACE);
+            if let Some(&(_, index, expiry)) = default {
+                write_index(state.borrow().init_index(), index)?;
+                write_expiring(state.borrow().init_expiring(expiry.len() as u32), expiry);
+            }
+
             {
-                let map = state.borrow().init_index();
-                let mut list = map.init_entries(index.len() as u32);
+                let mut list = state.borrow().init_keyspaces(others.len() as u32);
 
-                for (i, (key, &(ptr, _))) in index.iter().enumerate() {
+                for (i, &&(id, index, expiry)) in others.iter().enumerate() {
                     let mut entry = list.borrow().get(i as u32);
-                    entry.set_key(&**key)?;
-                    entry.init_value().set_pointer(ptr);
+                    entry.set_id(id);
+                    write_index(entry.borrow().init_index(), index)?;
+                    write_expiring(entry.borrow().init_expiring(expiry.len() as u32), expiry);
                 }
             }
 
@@ -75,6 +117,19 @@ impl DatastoreState {
                 }
             }
 
+            state.set_next_keyspace(next_keyspace);
+            state.set_sequence(sequence);
+
+            {
+                let mut list = state.borrow().init_strands(positions.len() as u32);
+
+                for (i, position) in positions.iter().enumerate() {
+                    let mut entry = list.borrow().get(i as u32);
+                    entry.set_generation(position.generation);
+                    entry.set_offset(position.offset);
+                }
+            }
+
             Ok(state)
         });
 
@@ -84,35 +139,37 @@ impl DatastoreState {
         }
     }
 
-    pub fn read(strand: &Strand, ptr: FilePointer) -> Result<VolumeState> {
+    pub fn read(strand: &Strand, ptr: FilePointer) -> Result<SavedState> {
         let mut reader = StrandReader::new(strand, ptr);
         let msg_reader = serialize_packed::read_message(&mut reader, ReaderOptions::new())?;
-        let state = msg_reader.get_root::<datastore_state::Reader>()?;
+        let item = msg_reader.get_root::<item::Reader>()?;
 
-        if state.get_signature() != serial_capnp::STATE_MAGIC {
+        if !item.has_state() {
             return Err(Error::Corrupt);
         }
 
-        let index = {
-            let mut index = IndexTree::new();
-            let map = state.get_index()?;
-            let list = map.get_entries()?;
+        let state = item.get_state()?;
+        if state.get_signature() != serial_capnp::STATE_MAGIC {
+            return Err(Error::Corrupt);
+        }
 
-            for entry in list.iter() {
-                let key = {
-                    let slice = entry.get_key()?;
-                    Vec::from(slice).into_boxed_slice()
-                };
+        let spaces = {
+            let mut spaces = BTreeMap::new();
+            let index = read_index(state.get_index()?)?;
+            let expiry = read_expiring(state.get_expiring()?, &index)?;
+            spaces.insert(DEFAULT_KEYSPACE, (index, expiry));
 
-                let ptr = entry.get_value()?.get_pointer();
+            for entry in state.get_keyspaces()?.iter() {
+                let index = read_index(entry.get_index()?)?;
+                let expiry = read_expiring(entry.get_expiring()?, &index)?;
 
-                if let Some(_) = index.insert(key, (ptr, false)) {
-                    // Duplicate item in index
+                if let Some(_) = spaces.insert(entry.get_id(), (index, expiry)) {
+                    // Duplicate keyspace
                     return Err(Error::Corrupt);
                 }
             }
 
-            Index::from(index)
+            spaces
         };
 
         let deleted = {
@@ -128,18 +185,108 @@ impl DatastoreState {
                 }
             }
 
-            Deleted::from(deleted)
+            deleted
         };
 
-        Ok(VolumeState::new(index, deleted))
+        let positions = state
+            .get_strands()?
+            .iter()
+            .map(|entry| {
+                StrandPosition {
+                    generation: entry.get_generation(),
+                    offset: entry.get_offset(),
+                }
+            })
+            .collect();
+
+        Ok(SavedState {
+            spaces: spaces,
+            deleted: deleted,
+            next_keyspace: state.get_next_keyspace(),
+            sequence: state.get_sequence(),
+            positions: positions,
+        })
     }
 
-    pub fn write(self, strand: &mut Strand) -> Result<()> {
+    pub fn write(self, strand: &mut Strand) -> Result<FilePointer> {
         let mut writer = StrandWriter::new(strand);
-        writer.update_offset = false;
+        let ptr = writer.get_pointer();
+
+        // The state must be on disk before the header points past it
         serialize_packed::write_message(&mut writer, &*self.0.into_head())?;
-        Ok(())
+        writer.flush()?;
+        writer.write_metadata()?;
+        Ok(ptr)
+    }
+}
+
+fn write_index(
+    map: map::Builder<data::Owned, file_pointer2::Owned>,
+    index: &IndexTree,
+) -> Result<()> {
+    let mut list = map.init_entries(index.len() as u32);
+
+    for (i, (key, &(ptr, _))) in index.iter().enumerate() {
+        let mut entry = list.borrow().get(i as u32);
+        entry.set_key(&**key)?;
+        entry.init_value().set_pointer(ptr);
     }
+
+    Ok(())
+}
+
+fn write_expiring(mut list: struct_list::Builder<expiring::Owned>, expiry: &ExpiryMap) {
+    for (i, (key, &time)) in expiry.iter().enumerate() {
+        let mut entry = list.borrow().get(i as u32);
+        entry.set_key(&**key);
+        entry.set_time(time);
+    }
+}
+
+fn read_index(map: map::Reader<data::Owned, file_pointer2::Owned>) -> Result<IndexTree> {
+    let mut index = IndexTree::new();
+
+    for entry in map.get_entries()?.iter() {
+        let key = {
+            let slice = entry.get_key()?;
+            Vec::from(slice).into_boxed_slice()
+        };
+
+        let ptr = entry.get_value()?.get_pointer();
+
+        if let Some(_) = index.insert(key, (ptr, false)) {
+            // Duplicate item in index
+            return Err(Error::Corrupt);
+        }
+    }
+
+    Ok(index)
+}
+
+fn read_expiring(
+    list: struct_list::Reader<expiring::Owned>,
+    index: &IndexTree,
+) -> Result<ExpiryMap> {
+    let mut expiry = ExpiryMap::new();
+
+    for entry in list.iter() {
+        let key = {
+            let slice = entry.get_key()?;
+            Vec::from(slice).into_boxed_slice()
+        };
+
+        // Only keys in the index can expire
+        if !index.contains_key(&key) {
+            return Err(Error::Corrupt);
+        }
+
+        if let Some(_) = expiry.insert(key, entry.get_time()) {
+            // Duplicate item
+            return Err(Error::Corrupt);
+        }
+    }
+
+    Ok(expiry)
 }
 
 impl fmt::Display for DatastoreState {
@@ -0,0 +1,208 @@
+/*
+ * snapshot.rs
+ *
+ * striking-db - Persistent key/value store for SSDs.
+ * Copyright (c) 2017 Maxwell Duzen, Ammon Smith
+ *
+ * striking-db is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Lesser General Public License as
+ * published by the Free Software Foundation, either version 2 of
+ * the License, or (at your option) any later version.
+ *
+ * striking-db is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public
+ * License along with striking-db.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+use super::{FilePointer, Result};
+use error::Error;
+use expiry::Expiry;
+use index::{Index, IndexTree};
+use iter::{Bounds, KeyRange};
+use serial::read_item;
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, btree_map};
+use volume::Volume;
+
+/// A read-only view of the datastore as it was at a point in time.
+/// See [`Store::snapshot`].
+///
+/// Changes made to the datastore after the snapshot was taken are not
+/// visible through it. It only covers the keyspace of the handle it was
+/// taken from; other keyspaces need snapshots of their own. The strands
+/// holding the items it can see are not vacuumed until it is dropped,
+/// and it holds a copy of every key, so it should not be kept longer
+/// than needed.
+///
+/// [`Store::snapshot`]: struct.Store.html#method.snapshot
+#[derive(Debug)]
+pub struct Snapshot<'s> {
+    volume: &'s Volume<'static>,
+    verify: bool,
+    index: IndexTree,
+    strands: Vec<u16>,
+}
+
+impl<'s> Snapshot<'s> {
+    pub(crate) fn new(
+        volume: &'s Volume<'static>,
+        index: &Index,
+        expiry: &Expiry,
+        verify: bool,
+    ) -> Self {
+        // No strand can be reset until the pins are in place
+        let mut pins = volume.pins();
+
+        // The freeze only waits out changes that are in flight. Once the
+        // tree is held for reading, new changes can start but can't
+        // update it, so the copy is made without holding up the others.
+        let frozen = index.freeze();
+        let tree = index.read();
+        drop(frozen);
+        let mut index = tree.clone();
+        drop(tree);
+
+        // Items that have already expired are not part of the snapshot
+        for key in expiry.due() {
+            index.remove(&key);
+        }
+
+        let ranges = volume.strand_ranges();
+        let strands = index
+            .values()
+            .map(|&(ptr, _)| {
+                let result = ranges.binary_search_by(|&(start, end)| if ptr < start {
+                    Ordering::Greater
+                } else if ptr < end {
+                    Ordering::Equal
+                } else {
+                    Ordering::Less
+                });
+
+                result.expect("Item is not in any strand") as u16
+            })
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>();
+
+        for &id in &strands {
+            pins[id as usize] += 1;
+        }
+
+        Snapshot {
+            volume: volume,
+            verify: verify,
+            index: index,
+            strands: strands,
+        }
+    }
+
+    /// Gets the given item as it was when the snapshot was taken.
+    /// See [`Store::lookup`].
+    ///
+    /// [`Store::lookup`]: struct.Store.html#method.lookup
+    pub fn lookup(&self, key: &[u8], val: &mut [u8]) -> Result<usize> {
+        let ptr = match self.index.get(key) {
+            Some(&(ptr, _)) => ptr,
+            None => return Err(Error::ItemNotFound),
+        };
+
+        self.volume.read(ptr, |strand| {
+            read_item(strand, ptr, self.verify, |ctx| ctx.copy_val(val))
+        })
+    }
+
+    /// Checks if the given item existed when the snapshot was taken.
+    pub fn exists(&self, key: &[u8]) -> bool {
+        self.index.contains_key(key)
+    }
+
+    /// Iterates over the items with keys in the given range, in key order.
+    /// See [`Store::range`], including on values that are too long to be
+    /// read this way. Use [`lookup`] to read part of one.
+    ///
+    /// [`Store::range`]: struct.Store.html#method.range
+    /// [`lookup`]: #method.lookup
+    pub fn range<R: KeyRange>(&self, range: R) -> SnapshotRange {
+        let bounds = Bounds::new(&range);
+        SnapshotRange::new(self, bounds)
+    }
+
+    /// Iterates over the items with keys that begin with `prefix`,
+    /// in key order. See [`Store::scan_prefix`].
+    ///
+    /// [`Store::scan_prefix`]: struct.Store.html#method.scan_prefix
+    pub fn scan_prefix(&self, prefix: &[u8]) -> SnapshotRange {
+        let bounds = Bounds::prefix(prefix);
+        SnapshotRange::new(self, bounds)
+    }
+
+    fn read_value(&self, ptr: FilePointer) -> Result<Vec<u8>> {
+        self.volume.read(ptr, |strand| {
+            read_item(strand, ptr, self.verify, |ctx| Ok(Vec::from(ctx.val()?)))
+        })
+    }
+}
+
+impl<'s> Drop for Snapshot<'s> {
+    fn drop(&mut self) {
+        let mut pins = self.volume.pins();
+
+        for &id in &self.strands {
+            pins[id as usize] -= 1;
+        }
+    }
+}
+
+/// An iterator over a range of items in a [`Snapshot`], in key order.
+/// It yields the key and value of each item, and may also be reversed.
+///
+/// [`Snapshot`]: struct.Snapshot.html
+#[derive(Debug)]
+pub struct SnapshotRange<'a> {
+    snapshot: &'a Snapshot<'a>,
+    entries: Option<btree_map::Range<'a, Box<[u8]>, (FilePointer, bool)>>,
+}
+
+impl<'a> SnapshotRange<'a> {
+    fn new(snapshot: &'a Snapshot<'a>, bounds: Bounds) -> Self {
+        SnapshotRange {
+            snapshot: snapshot,
+            entries: bounds.entries(&snapshot.index),
+        }
+    }
+
+    fn read(&self, key: &[u8], ptr: FilePointer) -> Result<(Vec<u8>, Vec<u8>)> {
+        let val = self.snapshot.read_value(ptr)?;
+        Ok((Vec::from(key), val))
+    }
+}
+
+impl<'a> Iterator for SnapshotRange<'a> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry = match self.entries {
+            Some(ref mut entries) => entries.next(),
+            None => None,
+        };
+
+        entry.map(|(key, &(ptr, _))| self.read(key, ptr))
+    }
+}
+
+impl<'a> DoubleEndedIterator for SnapshotRange<'a> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let entry = match self.entries {
+            Some(ref mut entries) => entries.next_back(),
+            None => None,
+        };
+
+        entry.map(|(key, &(ptr, _))| self.read(key, ptr))
+    }
+}
@@ -47,6 +47,49 @@ pub struct Stats {
     /// The number of deleted items in the datastore that
     /// have not been vacuumed yet.
     pub deleted_items: u64,
+
+    /// The number of reads whose value was found in the read cache.
+    pub cache_hits: u64,
+
+    /// The number of reads whose value was not in the read cache,
+    /// and so had to be read from the device.
+    pub cache_misses: u64,
+
+    /// The number of values added to the read cache.
+    pub cache_insertions: u64,
+
+    /// The number of values evicted from the read cache
+    /// to make room for others.
+    pub cache_evictions: u64,
+
+    /// The number of bytes currently held by the read cache,
+    /// counted the same way as [`OpenOptions::read_cache`].
+    ///
+    /// [`OpenOptions::read_cache`]: struct.OpenOptions.html#structfield.read_cache
+    pub cache_bytes: u64,
+}
+
+/// Describes the outcome of rebuilding the index from the items
+/// on disk. See [`OpenOptions::reindex`].
+///
+/// [`OpenOptions::reindex`]: struct.OpenOptions.html#structfield.reindex
+#[derive(Debug, Hash, Clone, Default, PartialEq, Eq)]
+pub struct ReindexReport {
+    /// The number of items recovered into the index.
+    pub recovered_items: u64,
+
+    /// The number of records that were either removals or
+    /// were replaced by a newer copy of the same key, and
+    /// were marked as deleted.
+    pub deleted_items: u64,
+
+    /// The number of strands containing a record that could
+    /// not be decoded. Since items are not aligned on disk,
+    /// the rest of such a strand cannot be read.
+    pub corrupt_strands: u64,
+
+    /// The number of bytes that were skipped in corrupt strands.
+    pub skipped_bytes: u64,
 }
 
 impl AddAssign for Stats {
@@ -58,5 +101,10 @@ impl AddAssign for Stats {
         self.buffer_written_bytes += rhs.buffer_written_bytes;
         self.valid_items += rhs.valid_items;
         self.deleted_items += rhs.deleted_items;
+        self.cache_hits += rhs.cache_hits;
+        self.cache_misses += rhs.cache_misses;
+        self.cache_insertions += rhs.cache_insertions;
+        self.cache_evictions += rhs.cache_evictions;
+        self.cache_bytes += rhs.cache_bytes;
     }
 }
@@ -19,67 +19,162 @@
  *
  */
 
-use super::{MAX_KEY_LEN, MAX_VAL_LEN, FilePointer, Result};
-use super::device::{Ssd, Memory};
+use super::{MAX_KEY_LEN, MAX_VAL_LEN, PAGE_SIZE, FilePointer, Result};
+use super::device::{Device, Ssd, Memory};
 use super::error::Error;
 use super::volume::Volume;
-use cache::ReadCache;
+use batch::{Operation, WriteBatch};
+use cache::{self, ReadCache, Uncached, ValueRef};
+use checkpoint::{self, Checkpoint};
 use deleted::Deleted;
-use index::Index;
+use expiry;
+use index::IndexEntryGuard;
+use iter::{Bounds, KeyRange, Keys, Range};
+use keyspace::{CATALOG, Keyspace, Space, Spaces};
 use options::OpenOptions;
-use serial::{DatastoreState, read_item, write_item};
-use stats::Stats;
+use serial::{Record, read_item, write_expiring_item, write_item, write_records, write_stream,
+             write_tombstone};
+use snapshot::Snapshot;
+use stats::{ReindexReport, Stats};
+use std::cmp::min;
+use std::collections::BTreeMap;
+use std::io::Read;
 use std::path::Path;
+use std::result;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 use strand::Strand;
+use transaction::{Transaction, Version};
+use vacuum::{self, Vacuum};
+use value::ValueReader;
+use volume::VolumeState;
+use worker::Worker;
 
 /// Represents an open handle to a datastore.
 ///
 /// This handle is thread-safe, and thus, is both [`Send`] and [`Sync`],
 /// and only requires a `&self` in order to operate on it.
 ///
-/// ## Panics
-/// When dropped, it writes the current state of the indexer and deleted
-/// items. If this fails, the destructor will panic.
+/// Every change is on disk by the time the method making it returns.
+/// If the process exits without the handle being dropped, the changes
+/// made since the last [`checkpoint`] are replayed on the next open.
+///
+/// If [`OpenOptions::vacuum`] or [`OpenOptions::checkpoint`] are set,
+/// the handle also owns background threads which vacuum the datastore
+/// or save checkpoints. They are stopped when the handle is dropped.
+///
+/// The handle should be closed with [`close`], which saves a final
+/// checkpoint and reports any errors. If it is dropped instead, this
+/// is still attempted, but errors are only printed to standard error.
 ///
 /// [`Send`]: https://doc.rust-lang.org/stable/std/marker/trait.Send.html
 /// [`Sync`]: https://doc.rust-lang.org/stable/std/marker/trait.Sync.html
+/// [`checkpoint`]: #method.checkpoint
+/// [`close`]: #method.close
+/// [`OpenOptions::vacuum`]: struct.OpenOptions.html#structfield.vacuum
+/// [`OpenOptions::checkpoint`]: struct.OpenOptions.html#structfield.checkpoint
 #[derive(Debug)]
-pub struct Store<'a> {
-    volume: Volume<'a>,
-    index: Index,
-    deleted: Deleted,
-    cache: ReadCache,
+pub struct Store {
+    volume: Arc<Volume<'static>>,
+    space: Arc<Space>,
+    spaces: Arc<Spaces>,
+    deleted: Arc<Deleted>,
+    cache: Arc<ReadCache>,
+    worker: Option<Worker>,
+    checkpointer: Option<Worker>,
+    report: Option<ReindexReport>,
+    verify: bool,
+    fill_cache: bool,
+    closed: bool,
 }
 
-impl<'a> Store<'a> {
+impl Store {
     pub fn open<P: AsRef<Path>>(path: P, options: &OpenOptions) -> Result<Self> {
         let ssd = Ssd::open(path.as_ref())?;
         let (volume, state) = Volume::open(Box::new(ssd), options)?;
-        let (index, deleted) = state.extract();
 
-        Ok(Store {
-            volume: volume,
-            index: index,
-            deleted: deleted,
-            cache: ReadCache::new(),
-        })
+        Ok(Self::from_volume(volume, state, options))
     }
 
     pub fn memory(bytes: usize, options: &OpenOptions) -> Result<Self> {
         let memory = Memory::new(bytes);
         let (volume, _) = Volume::open(Box::new(memory), options)?;
 
-        Ok(Store {
+        Ok(Self::from_volume(volume, VolumeState::default(), options))
+    }
+
+    /// Opens a datastore on the given device, which may be any type
+    /// implementing [`Device`]. This is how custom backends, such as
+    /// test doubles or wrappers around [`Ssd`] and [`Memory`], are used.
+    ///
+    /// If the capacity of the device is not a multiple of [`PAGE_SIZE`],
+    /// then [`Error::BadArgument`] is returned.
+    ///
+    /// [`Device`]: trait.Device.html
+    /// [`Ssd`]: struct.Ssd.html
+    /// [`Memory`]: struct.Memory.html
+    /// [`PAGE_SIZE`]: constant.PAGE_SIZE.html
+    /// [`Error::BadArgument`]: enum.Error.html
+    pub fn with_device(device: Box<Device>, options: &OpenOptions) -> Result<Self> {
+        if device.capacity() % PAGE_SIZE as u64 != 0 {
+            return Err(Error::BadArgument("Device capacity is not a multiple of the page size."));
+        }
+
+        let (volume, state) = Volume::open(device, options)?;
+        Ok(Self::from_volume(volume, state, options))
+    }
+
+    fn from_volume(volume: Volume<'static>, state: VolumeState, options: &OpenOptions) -> Self {
+        let (spaces, deleted, report) = state.extract();
+        let volume = Arc::new(volume);
+        let space = spaces.default_space();
+        let spaces = Arc::new(spaces);
+        let deleted = Arc::new(deleted);
+        let cache = Arc::new(ReadCache::new(
+            options.read_cache.unwrap_or(cache::DEFAULT_CAPACITY),
+            options.read_cache_max_value.unwrap_or(cache::DEFAULT_MAX_VALUE),
+        ));
+
+        let worker = match options.vacuum {
+            Some(ref vacuum) => Some(vacuum::spawn_worker(
+                volume.clone(),
+                spaces.clone(),
+                deleted.clone(),
+                cache.clone(),
+                vacuum.clone(),
+            )),
+            None => None,
+        };
+
+        let checkpointer = match options.checkpoint {
+            Some(interval) => Some(checkpoint::spawn_worker(
+                volume.clone(),
+                spaces.clone(),
+                deleted.clone(),
+                interval,
+            )),
+            None => None,
+        };
+
+        Store {
             volume: volume,
-            index: Index::new(),
-            deleted: Deleted::new(),
-            cache: ReadCache::new(),
-        })
+            space: space,
+            spaces: spaces,
+            deleted: deleted,
+            cache: cache,
+            worker: worker,
+            checkpointer: checkpointer,
+            report: report,
+            verify: !options.skip_checksums,
+            fill_cache: true,
+            closed: false,
+        }
     }
 
     // Helper methods
     #[inline]
-    fn verify_key(key: &[u8]) -> Result<()> {
+    pub(crate) fn verify_key(key: &[u8]) -> Result<()> {
         if key.is_empty() || key.len() > MAX_KEY_LEN {
             Err(Error::InvalidKey)
         } else {
@@ -88,7 +183,7 @@ impl<'a> Store<'a> {
     }
 
     #[inline]
-    fn verify_val(val: &[u8]) -> Result<()> {
+    pub(crate) fn verify_val(val: &[u8]) -> Result<()> {
         if val.len() > MAX_VAL_LEN {
             Err(Error::InvalidValue)
         } else {
@@ -101,19 +196,35 @@ impl<'a> Store<'a> {
     /// This method searches for the item associated with the passed
     /// `key`. If found, then as much of the value as will fit is
     /// copied into `val`, and the number of bytes written is returned.
+    /// Use [`value_len`] to find out how large the value is, or [`get`]
+    /// to retrieve all of it.
     ///
     /// If there is no such item, then [`Error::ItemNotFound`] is returned.
+    /// If the item fails its checksum, then [`Error::CorruptItem`] is
+    /// returned, unless [`OpenOptions::skip_checksums`] is set. Large values
+    /// are checked in chunks, so only the part that fits in `val` is read.
     ///
     /// [`Error::ItemNotFound`]: enum.Error.html
+    /// [`Error::CorruptItem`]: enum.Error.html
+    /// [`OpenOptions::skip_checksums`]: struct.OpenOptions.html#structfield.skip_checksums
+    /// [`value_len`]: #method.value_len
+    /// [`get`]: #method.get
     pub fn lookup(&self, key: &[u8], val: &mut [u8]) -> Result<usize> {
         Self::verify_key(key)?;
 
-        if let Some(len) = self.cache.get(key, val) {
+        // The cache may still hold the value of an expired item
+        if self.space.expiry.expired(key) {
+            return Err(Error::ItemNotFound);
+        }
+
+        if let Some(cached) = self.cached(key) {
+            let len = min(val.len(), cached.len());
+            val[..len].copy_from_slice(&cached[..len]);
             return Ok(len);
         }
 
-        let entry = self.index.lock(key);
-        let ptr = match entry.value {
+        let entry = self.lock(key)?;
+        let ptr = match self.live(&entry) {
             Some(ptr) => ptr,
             None => return Err(Error::ItemNotFound),
         };
@@ -124,12 +235,243 @@ impl<'a> Store<'a> {
         )
     }
 
+    /// Gets the value of the given item.
+    ///
+    /// Unlike [`lookup`], the whole value is always returned. If there
+    /// is no such item, then [`Error::ItemNotFound`] is returned. If the
+    /// value is longer than [`MAX_READ_LEN`], then [`Error::ValueTooLarge`]
+    /// is returned, and it should be read with [`open_value`] or
+    /// [`read_range`] instead.
+    ///
+    /// [`lookup`]: #method.lookup
+    /// [`open_value`]: #method.open_value
+    /// [`read_range`]: #method.read_range
+    /// [`MAX_READ_LEN`]: constant.MAX_READ_LEN.html
+    /// [`Error::ItemNotFound`]: enum.Error.html
+    /// [`Error::ValueTooLarge`]: enum.Error.html
+    pub fn get(&self, key: &[u8]) -> Result<Vec<u8>> {
+        Self::verify_key(key)?;
+
+        if self.space.expiry.expired(key) {
+            return Err(Error::ItemNotFound);
+        }
+
+        if let Some(val) = self.cached(key) {
+            return Ok(val.to_vec());
+        }
+
+        let entry = self.lock(key)?;
+        let ptr = match self.live(&entry) {
+            Some(ptr) => ptr,
+            None => return Err(Error::ItemNotFound),
+        };
+
+        // Values that aren't cached are read straight into the returned buffer
+        self.volume.read(ptr, |strand| {
+            read_item(strand, ptr, self.verify, |ctx| {
+                match self.cache.fill(self.fill_cache, self.space.id(), &ctx)? {
+                    Some(val) => Ok(val.to_vec()),
+                    None => Ok(ctx.val()?.into_owned()),
+                }
+            })
+        })
+    }
+
+    /// Gets a shared reference to the value of the given item.
+    ///
+    /// The value is kept in the read cache, so reading the same item
+    /// again while it is cached does not need to copy or allocate.
+    /// See [`get`] for more information, including on values that are
+    /// too long to be read this way.
+    ///
+    /// [`get`]: #method.get
+    pub fn get_ref(&self, key: &[u8]) -> Result<ValueRef> {
+        Self::verify_key(key)?;
+
+        if self.space.expiry.expired(key) {
+            return Err(Error::ItemNotFound);
+        }
+
+        if let Some(val) = self.cached(key) {
+            return Ok(val);
+        }
+
+        let entry = self.lock(key)?;
+        let ptr = match self.live(&entry) {
+            Some(ptr) => ptr,
+            None => return Err(Error::ItemNotFound),
+        };
+
+        self.volume.read(ptr, |strand| {
+            read_item(strand, ptr, self.verify, |ctx| {
+                match self.cache.fill(self.fill_cache, self.space.id(), &ctx)? {
+                    Some(val) => Ok(val),
+                    None => Ok(ValueRef::new(&ctx.val()?)),
+                }
+            })
+        })
+    }
+
+    /// Returns the length of the given item's value, in bytes.
+    ///
+    /// This can be used to size the buffer passed to [`lookup`].
+    /// If there is no such item, then [`Error::ItemNotFound`] is returned.
+    ///
+    /// [`lookup`]: #method.lookup
+    /// [`Error::ItemNotFound`]: enum.Error.html
+    pub fn value_len(&self, key: &[u8]) -> Result<usize> {
+        Self::verify_key(key)?;
+
+        if self.space.expiry.expired(key) {
+            return Err(Error::ItemNotFound);
+        }
+
+        if let Some(val) = self.cached(key) {
+            return Ok(val.len());
+        }
+
+        let entry = self.lock(key)?;
+        let ptr = match self.live(&entry) {
+            Some(ptr) => ptr,
+            None => return Err(Error::ItemNotFound),
+        };
+
+        self.volume.read(ptr, |strand| {
+            read_item(strand, ptr, self.verify, |ctx| ctx.val_len())
+        })
+    }
+
+    /// Opens the value of the given item for reading.
+    ///
+    /// The returned [`ValueReader`] implements [`Read`] and [`Seek`].
+    /// Values written with [`put_stream`] are read from disk as needed,
+    /// rather than all at once. If there is no such item, then
+    /// [`Error::ItemNotFound`] is returned.
+    ///
+    /// [`ValueReader`]: struct.ValueReader.html
+    /// [`Read`]: https://doc.rust-lang.org/stable/std/io/trait.Read.html
+    /// [`Seek`]: https://doc.rust-lang.org/stable/std/io/trait.Seek.html
+    /// [`put_stream`]: #method.put_stream
+    /// [`Error::ItemNotFound`]: enum.Error.html
+    pub fn open_value(&self, key: &[u8]) -> Result<ValueReader> {
+        Self::verify_key(key)?;
+
+        if self.space.expiry.expired(key) {
+            return Err(Error::ItemNotFound);
+        }
+
+        let entry = self.lock(key)?;
+        let ptr = match self.live(&entry) {
+            Some(ptr) => ptr,
+            None => return Err(Error::ItemNotFound),
+        };
+
+        ValueReader::new(&*self.volume, ptr, self.verify)
+    }
+
+    /// Reads part of the value of the given item.
+    ///
+    /// As much of the value as will fit is copied into `buf`, starting
+    /// `offset` bytes into it, and the number of bytes copied is returned.
+    /// If the offset is past the end of the value, then nothing is copied.
+    /// Large values are stored in chunks of 64 KiB, each with its own
+    /// checksum, so only the chunks holding the requested bytes are read.
+    ///
+    /// If there is no such item, then [`Error::ItemNotFound`] is returned.
+    /// If any of the chunks read fails its checksum, then
+    /// [`Error::CorruptItem`] is returned, unless
+    /// [`OpenOptions::skip_checksums`] is set.
+    ///
+    /// [`Error::ItemNotFound`]: enum.Error.html
+    /// [`Error::CorruptItem`]: enum.Error.html
+    /// [`OpenOptions::skip_checksums`]: struct.OpenOptions.html#structfield.skip_checksums
+    pub fn read_range(&self, key: &[u8], offset: u64, buf: &mut [u8]) -> Result<usize> {
+        Self::verify_key(key)?;
+
+        if self.space.expiry.expired(key) {
+            return Err(Error::ItemNotFound);
+        }
+
+        let entry = self.lock(key)?;
+        let ptr = match self.live(&entry) {
+            Some(ptr) => ptr,
+            None => return Err(Error::ItemNotFound),
+        };
+
+        self.volume.read(ptr, |strand| {
+            read_item(strand, ptr, self.verify, |ctx| ctx.copy_val_range(offset, buf))
+        })
+    }
+
     /// Checks if the given item exists in the datastore.
     /// This is a fast check, since it only inspects the
     /// in-memory index, and does not need to go to disk
     /// to fetch any data.
     pub fn exists(&self, key: &[u8]) -> bool {
-        self.index.exists(key)
+        self.space.index.exists(key) && !self.space.expiry.expired(key)
+    }
+
+    /// Iterates over the items with keys in the given range, in key order.
+    /// Each item is yielded as a pair of its key and value.
+    ///
+    /// The iterator may also be reversed to go from the highest key to the
+    /// lowest. Items are read as the iterator advances, so changes made
+    /// during iteration may or may not be seen. If an item is in the middle
+    /// of being changed, then the iterator waits for the change to finish.
+    ///
+    /// Values longer than [`MAX_READ_LEN`] are yielded as
+    /// [`Error::ValueTooLarge`], and should be read with [`open_value`] or
+    /// [`read_range`] instead. Iteration carries on past them.
+    ///
+    /// [`open_value`]: #method.open_value
+    /// [`read_range`]: #method.read_range
+    /// [`MAX_READ_LEN`]: constant.MAX_READ_LEN.html
+    /// [`Error::ValueTooLarge`]: enum.Error.html
+    pub fn range<R: KeyRange>(&self, range: R) -> Range {
+        let bounds = Bounds::new(&range);
+        Range::new(
+            &*self.volume,
+            &self.space,
+            &self.cache,
+            self.verify,
+            self.fill_cache,
+            bounds,
+        )
+    }
+
+    /// Iterates over the items with keys that begin with `prefix`,
+    /// in key order. See [`range`] for more information.
+    ///
+    /// [`range`]: #method.range
+    pub fn scan_prefix(&self, prefix: &[u8]) -> Range {
+        let bounds = Bounds::prefix(prefix);
+        Range::new(
+            &*self.volume,
+            &self.space,
+            &self.cache,
+            self.verify,
+            self.fill_cache,
+            bounds,
+        )
+    }
+
+    /// Iterates over the keys in the given range, in key order.
+    ///
+    /// Like [`exists`], this only inspects the in-memory index, and
+    /// does not need to go to disk. Keys that are in the middle of
+    /// being inserted are not yielded.
+    ///
+    /// [`exists`]: #method.exists
+    pub fn keys<R: KeyRange>(&self, range: R) -> Keys {
+        Keys::new(&self.space.index, &self.space.expiry, Bounds::new(&range))
+    }
+
+    /// Iterates over the keys that begin with `prefix`, in key order.
+    /// See [`keys`] for more information.
+    ///
+    /// [`keys`]: #method.keys
+    pub fn keys_with_prefix(&self, prefix: &[u8]) -> Keys {
+        Keys::new(&self.space.index, &self.space.expiry, Bounds::prefix(prefix))
     }
 
     /// Inserts an item into the datastore.
@@ -143,19 +485,17 @@ impl<'a> Store<'a> {
         Self::verify_key(key)?;
         Self::verify_val(val)?;
 
-        let mut entry = self.index.lock(key);
-        if entry.exists() {
+        let mut entry = self.lock(key)?;
+        if self.live(&entry).is_some() {
             return Err(Error::ItemExists);
         }
 
-        let ptr = self.volume.write(|strand| {
-            {
-                let stats = &mut strand.stats.lock();
-                stats.valid_items += 1;
-            }
+        let ptr = self.volume.write(|strand| write_item(strand, self.space.id(), key, val))?;
 
-            write_item(strand, key, val)
-        })?;
+        // Replace the item if it has expired
+        if let Some(old_ptr) = entry.value {
+            self.remove_item(key, old_ptr);
+        }
 
         entry.value = Some(ptr);
         Ok(())
@@ -172,21 +512,13 @@ impl<'a> Store<'a> {
         Self::verify_key(key)?;
         Self::verify_val(val)?;
 
-        let mut entry = self.index.lock(key);
-        let old_ptr = match entry.value {
+        let mut entry = self.lock(key)?;
+        let old_ptr = match self.live(&entry) {
             Some(ptr) => ptr,
             None => return Err(Error::ItemNotFound),
         };
 
-        let ptr = self.volume.write(|strand| {
-            {
-                let stats = &mut strand.stats.lock();
-                stats.valid_items += 1;
-                stats.deleted_items += 1;
-            }
-
-            write_item(strand, key, val)
-        })?;
+        let ptr = self.volume.write(|strand| write_item(strand, self.space.id(), key, val))?;
 
         self.remove_item(key, old_ptr);
         entry.value = Some(ptr);
@@ -202,18 +534,42 @@ impl<'a> Store<'a> {
         Self::verify_key(key)?;
         Self::verify_val(val)?;
 
-        let mut entry = self.index.lock(key);
-        let ptr = self.volume.write(|strand| {
-            {
-                let stats = &mut strand.stats.lock();
-                stats.valid_items += 1;
-                if entry.exists() {
-                    stats.deleted_items += 1
-                }
-            }
+        let mut entry = self.lock(key)?;
+        let ptr = self.volume.write(|strand| write_item(strand, self.space.id(), key, val))?;
 
-            write_item(strand, key, val)
-        })?;
+        if let Some(old_ptr) = entry.value {
+            self.remove_item(key, old_ptr);
+        }
+
+        entry.value = Some(ptr);
+        Ok(())
+    }
+
+    /// Puts an item in the datastore, reading its value from `reader`.
+    ///
+    /// Exactly `len` bytes are read and written to disk as they arrive,
+    /// so large values don't have to fit in memory. If the reader ends
+    /// early or fails, then nothing is changed. Otherwise this behaves
+    /// like [`put`]. Use [`open_value`] to read the value back.
+    ///
+    /// Nothing is locked while waiting on `reader`, so it may be reading
+    /// another value from this datastore, such as one opened with
+    /// [`open_value`], and the same key may be written in the meantime.
+    /// The item is only put once all of its value is on disk, and it
+    /// replaces whatever the key held at that point.
+    ///
+    /// [`put`]: #method.put
+    /// [`open_value`]: #method.open_value
+    pub fn put_stream<R: Read>(&self, key: &[u8], mut reader: R, len: u64) -> Result<()> {
+        Self::verify_key(key)?;
+
+        if len > MAX_VAL_LEN as u64 {
+            return Err(Error::InvalidValue);
+        }
+
+        let space = self.space.id();
+        let lock = || self.lock(key);
+        let (ptr, mut entry) = write_stream(&self.volume, space, key, &mut reader, len, lock)?;
 
         if let Some(old_ptr) = entry.value {
             self.remove_item(key, old_ptr);
@@ -223,6 +579,33 @@ impl<'a> Store<'a> {
         Ok(())
     }
 
+    /// Puts an item in the datastore that expires after the given duration.
+    ///
+    /// This is like [`put`], except that once `ttl` has passed, the item
+    /// is treated as if it had been removed. Its space is reclaimed the
+    /// next time the datastore is vacuumed. Changing the item in any other
+    /// way, such as with another call to [`put`], clears its expiry.
+    ///
+    /// [`put`]: #method.put
+    pub fn put_with_ttl(&self, key: &[u8], val: &[u8], ttl: Duration) -> Result<()> {
+        Self::verify_key(key)?;
+        Self::verify_val(val)?;
+
+        let expires = expiry::deadline(ttl);
+        let mut entry = self.lock(key)?;
+        let ptr = self.volume.write(
+            |strand| write_expiring_item(strand, self.space.id(), key, val, expires),
+        )?;
+
+        if let Some(old_ptr) = entry.value {
+            self.remove_item(key, old_ptr);
+        }
+
+        self.space.expiry.set(key, expires);
+        entry.value = Some(ptr);
+        Ok(())
+    }
+
     /// Removes an item from the datastore.
     ///
     /// This will remove the data associated with the
@@ -235,14 +618,11 @@ impl<'a> Store<'a> {
     pub fn remove(&self, key: &[u8]) -> Result<()> {
         Self::verify_key(key)?;
 
-        let entry = self.index.lock(key);
+        let mut entry = self.lock(key)?;
         if let Some(ptr) = entry.value {
-            self.volume.read(ptr, |strand| {
-                let stats = &mut strand.stats.lock();
-                stats.deleted_items += 1;
-            });
-
+            self.bury_item(key)?;
             self.remove_item(key, ptr);
+            entry.value = None;
         }
 
         Ok(())
@@ -265,27 +645,133 @@ impl<'a> Store<'a> {
     pub fn delete(&self, key: &[u8], val: &mut [u8]) -> Result<usize> {
         Self::verify_key(key)?;
 
-        let mut entry = self.index.lock(key);
-        let ptr = match entry.value {
+        let mut entry = self.lock(key)?;
+        let ptr = match self.live(&entry) {
             Some(ptr) => ptr,
             None => return Err(Error::ItemNotFound),
         };
 
+        // Read before the item is removed, so that the cached value can be
+        // used. A read from disk doesn't add to the cache, which it's leaving.
+        let len = match self.cache.peek(self.space.id(), key) {
+            Some(cached) => {
+                let len = min(val.len(), cached.len());
+                val[..len].copy_from_slice(&cached[..len]);
+                len
+            }
+            None => self.volume.read(ptr, |strand| {
+                read_item(strand, ptr, self.verify, |ctx| ctx.copy_val(val))
+            })?,
+        };
+
+        self.bury_item(key)?;
         self.remove_item(key, ptr);
         entry.value = None;
+        Ok(len)
+    }
 
-        if let Some(len) = self.cache.get(key, val) {
-            return Ok(len);
+    /// Changes an item, but only if its current value is as expected.
+    ///
+    /// If `expected` is `None`, then the item must not exist, otherwise
+    /// its value must be equal to `expected`. If so, then the item is
+    /// set to `new`, or removed if `new` is `None`. If not, then nothing
+    /// is changed and [`Error::Mismatch`] is returned. The current value
+    /// is compared a chunk at a time, so it is never read into memory.
+    ///
+    /// [`Error::Mismatch`]: enum.Error.html
+    pub fn compare_and_swap(
+        &self,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<&[u8]>,
+    ) -> Result<()> {
+        Self::verify_key(key)?;
+        if let Some(val) = new {
+            Self::verify_val(val)?;
         }
 
-        self.volume.read(ptr, |strand| {
-            {
-                let stats = &mut strand.stats.lock();
-                stats.deleted_items += 1;
+        let mut entry = self.lock(key)?;
+        let matches = match (self.live(&entry), expected) {
+            (Some(ptr), Some(expected)) => {
+                self.volume.read(ptr, |strand| {
+                    read_item(strand, ptr, self.verify, |ctx| ctx.val_eq(expected))
+                })?
             }
+            (None, None) => true,
+            _ => false,
+        };
 
-            self.lookup_item(strand, ptr, val)
-        })
+        if !matches {
+            return Err(Error::Mismatch);
+        }
+
+        match new {
+            Some(val) => {
+                let ptr = self.volume.write(
+                    |strand| write_item(strand, self.space.id(), key, val),
+                )?;
+
+                if let Some(old_ptr) = entry.value {
+                    self.remove_item(key, old_ptr);
+                }
+
+                entry.value = Some(ptr);
+            }
+            None => {
+                if let Some(old_ptr) = entry.value {
+                    self.bury_item(key)?;
+                    self.remove_item(key, old_ptr);
+                    entry.value = None;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Applies every change in the batch atomically.
+    ///
+    /// Either all of the changes are made, or none of them are, even if
+    /// the process exits while the batch is being written. If an item to
+    /// be inserted already exists, then [`Error::ItemExists`] is returned
+    /// and nothing is changed.
+    ///
+    /// [`Error::ItemExists`]: enum.Error.html
+    pub fn write_batch(&self, batch: &WriteBatch) -> Result<()> {
+        Self::verify_batch(batch)?;
+
+        let keys = batch.iter().map(|(key, _)| &**key).collect::<Vec<_>>();
+        let mut entries = self.lock_all(&keys)?;
+        self.apply_batch(batch, &mut entries)
+    }
+
+    /// Runs a read-modify-write transaction over any number of items.
+    ///
+    /// The closure reads and changes items through the given
+    /// [`Transaction`]. Its changes are only applied once it returns,
+    /// and only if none of the items it read have been changed in the
+    /// meantime. Otherwise, the closure is run again until it succeeds.
+    /// No items are locked while the closure runs, so it may be called
+    /// several times when there is contention.
+    ///
+    /// If the closure returns an error, it is returned and nothing is
+    /// changed.
+    ///
+    /// [`Transaction`]: struct.Transaction.html
+    pub fn transaction<F, R>(&self, mut func: F) -> Result<R>
+    where
+        F: FnMut(&mut Transaction) -> Result<R>,
+    {
+        loop {
+            let mut txn = Transaction::new(self);
+            let result = func(&mut txn)?;
+
+            match txn.commit() {
+                Ok(()) => return Ok(result),
+                Err(Error::Conflict) => thread::yield_now(),
+                Err(err) => return Err(err),
+            }
+        }
     }
 
     /// Performs an atomic read-modify-write on the given item.
@@ -313,91 +799,548 @@ impl<'a> Store<'a> {
     pub fn merge<F>(&self, key: &[u8], func: F) -> Result<()>
     where
         F: FnOnce(Option<Vec<u8>>) -> Option<Vec<u8>>,
+    {
+        self.try_merge::<_, _, Error>(key, |val| Ok((func(val), ())))
+    }
+
+    /// Like [`merge`], but the given function may fail, and may
+    /// return a result of its own alongside the new value.
+    ///
+    /// If the function returns an error, the item is left untouched
+    /// and the error is passed back to the caller. Otherwise, its
+    /// result is returned once the change has been written. Errors
+    /// from the datastore itself are converted into the same error type.
+    ///
+    /// The current value is read into memory to be passed to the function,
+    /// so if it is longer than [`MAX_READ_LEN`], then [`Error::ValueTooLarge`]
+    /// is returned and the function isn't called.
+    ///
+    /// [`merge`]: #method.merge
+    /// [`MAX_READ_LEN`]: constant.MAX_READ_LEN.html
+    /// [`Error::ValueTooLarge`]: enum.Error.html
+    pub fn try_merge<F, R, E>(&self, key: &[u8], func: F) -> result::Result<R, E>
+    where
+        F: FnOnce(Option<Vec<u8>>) -> result::Result<(Option<Vec<u8>>, R), E>,
+        E: From<Error>,
     {
         Self::verify_key(key)?;
 
-        let mut entry = self.index.lock(key);
+        let mut entry = self.lock(key)?;
+        let old_ptr = entry.value;
 
         // Read a value from the store if it's there, and return it in a vec
-        let val = match entry.value {
-            Some(ptr) => {
-                let val_buffer = self.volume.read(ptr, |strand| {
-                    read_item(strand, ptr, |ctx| Ok(Vec::from(ctx.val()?)))
-                })?;
-
-                // NOTE: "updates" are really just a removal and an insert
-                self.remove_item(key, ptr);
-
-                Some(val_buffer)
-            }
+        let val = match self.live(&entry) {
+            Some(ptr) => Some(self.volume.read(ptr, |strand| {
+                read_item(strand, ptr, self.verify, |ctx| Ok(Vec::from(ctx.val()?)))
+            })?),
             None => None,
         };
 
-        // Call the user's function...
-        let result = func(val);
+        // Call the user's function, nothing has been changed yet
+        let (new, output) = func(val)?;
 
         // Write it back!
-        self.volume.write(|strand| {
-            // Update stats
-            {
-                let stats = strand.stats.get_mut();
-                if entry.exists() {
-                    stats.deleted_items += 1;
-                }
-                if result.is_some() {
-                    stats.valid_items += 1;
+        match new {
+            Some(ref val) => {
+                Self::verify_val(val)?;
+                let ptr = self.volume.write(
+                    |strand| write_item(strand, self.space.id(), key, val),
+                )?;
+                entry.value = Some(ptr);
+            }
+            None => {
+                if old_ptr.is_some() {
+                    self.bury_item(key)?;
                 }
+
+                entry.value = None;
             }
+        }
+
+        // NOTE: "updates" are really just a removal and an insert
+        if let Some(ptr) = old_ptr {
+            self.remove_item(key, ptr);
+        }
+
+        Ok(output)
+    }
+
+    /// Opens the keyspace with the given name, creating it if needed.
+    ///
+    /// A keyspace is a separate set of items within the same volume, so
+    /// the same key may be used in several keyspaces without conflicting.
+    /// The returned [`Keyspace`] has all of the methods of the store, which
+    /// only see and change the items in that keyspace. Every keyspace
+    /// shares the strands and read cache of the volume.
+    ///
+    /// If the name is empty or too long, [`Error::BadArgument`] is returned.
+    ///
+    /// [`Keyspace`]: struct.Keyspace.html
+    /// [`Error::BadArgument`]: enum.Error.html
+    pub fn keyspace(&self, name: &str) -> Result<Keyspace> {
+        Self::verify_name(name)?;
+
+        if let Some(space) = self.spaces.find(name) {
+            return Ok(Keyspace::new(self.with_space(space), name));
+        }
+
+        let mut registry = self.spaces.write();
+
+        // It may have been created while we waited for the lock
+        if let Some(space) = registry.find(name) {
+            return Ok(Keyspace::new(self.with_space(space), name));
+        }
 
-            entry.value = match result {
-                Some(ref val) => Some(write_item(strand, key, val.as_slice())?),
-                None => None,
+        let id = registry.next_id();
+        if id == CATALOG {
+            return Err(Error::BadArgument("Too many keyspaces have been created."));
+        }
+
+        // The name is on disk before anything can be put in the keyspace
+        let catalog = registry.catalog().clone();
+        {
+            let key = name.as_bytes();
+            let val = [id as u8, (id >> 8) as u8, (id >> 16) as u8, (id >> 24) as u8];
+            let mut entry = catalog.index.lock(key);
+            debug_assert!(entry.value.is_none(), "Keyspace name is still in the catalog");
+
+            let ptr = self.volume.write(|strand| write_item(strand, CATALOG, key, &val))?;
+            entry.value = Some(ptr);
+        }
+
+        let space = registry.create(name);
+        Ok(Keyspace::new(self.with_space(space), name))
+    }
+
+    /// Drops the keyspace with the given name, removing all of its items.
+    ///
+    /// Only the removal of the name is written to disk, and other keyspaces
+    /// are only blocked while that happens. The keyspace's items are then
+    /// released from memory one by one, so this takes time in proportion
+    /// to how many there are, and no strand is vacuumed until it is done.
+    /// Their space is reclaimed the next time the datastore is vacuumed.
+    /// Any handles to the keyspace that are still open return
+    /// [`Error::KeyspaceNotFound`] when used, as does this method if there
+    /// is no such keyspace.
+    ///
+    /// [`Error::KeyspaceNotFound`]: enum.Error.html
+    pub fn drop_keyspace(&self, name: &str) -> Result<()> {
+        Self::verify_name(name)?;
+
+        // Once the name is gone, the items are no longer relocated, but they
+        // aren't marked as deleted until afterwards. No strand may be reset
+        // in between. Pinned before freezing, in the same order as snapshots.
+        for pin in self.volume.pins().iter_mut() {
+            *pin += 1;
+        }
+
+        let result = self.unregister(name).map(|space| {
+            let items = {
+                // Waits for any changes in progress. Later ones see that it was dropped.
+                let _frozen = space.index.freeze();
+                space.set_dropped();
+                space.index.drain()
             };
 
-            Ok(())
-        })
+            for (key, (ptr, _)) in items {
+                self.volume.read(ptr, |strand| strand.mark_deleted());
+                self.cache.remove(space.id(), &key);
+                self.deleted.add(ptr);
+            }
+        });
+
+        for pin in self.volume.pins().iter_mut() {
+            *pin -= 1;
+        }
+
+        result
+    }
+
+    // Removes the keyspace's name from the registry, and from the catalog on
+    // disk, so that it can't be found again. Its items are left as they are.
+    fn unregister(&self, name: &str) -> Result<Arc<Space>> {
+        let mut registry = self.spaces.write();
+        let space = match registry.find(name) {
+            Some(space) => space,
+            None => return Err(Error::KeyspaceNotFound),
+        };
+
+        let catalog = registry.catalog().clone();
+        {
+            let key = name.as_bytes();
+            let mut entry = catalog.index.lock(key);
+            let tombstone = self.volume.write(|strand| write_tombstone(strand, CATALOG, key))?;
+            self.deleted.add(tombstone);
+
+            if let Some(ptr) = entry.value {
+                self.volume.read(ptr, |strand| strand.mark_deleted());
+                self.deleted.add(ptr);
+            }
+
+            entry.value = None;
+        }
+
+        registry.remove(name);
+        Ok(space)
+    }
+
+    /// Returns a handle whose reads don't add items to the read cache.
+    ///
+    /// This is meant for bulk reads, like exports and full scans, that
+    /// would otherwise take the place of the items that are actually
+    /// in use. Items that are already cached are still read from it.
+    /// The returned [`Uncached`] has all of the methods of the store,
+    /// and sees the same keyspace. See also [`Range::fill_cache`].
+    ///
+    /// [`Uncached`]: struct.Uncached.html
+    /// [`Range::fill_cache`]: struct.Range.html#method.fill_cache
+    pub fn uncached(&self) -> Uncached {
+        let mut store = self.with_space(self.space.clone());
+        store.fill_cache = false;
+        Uncached::new(store)
+    }
+
+    /// Takes a snapshot of the datastore.
+    ///
+    /// The returned [`Snapshot`] sees every item in this handle's keyspace
+    /// exactly as it was at this point, regardless of any later changes.
+    /// Other keyspaces are not part of it, and separate snapshots of
+    /// them are not taken at the same point. Strands holding items that
+    /// the snapshot can see are not vacuumed until it is dropped.
+    ///
+    /// The snapshot keeps its own copy of the keyspace's in-memory index,
+    /// so taking one costs time and memory in proportion to the number of
+    /// items. New changes to the keyspace may be started while the index
+    /// is copied, but wait for the copy before completing. Values are
+    /// not copied, since they are read from disk as needed.
+    ///
+    /// [`Snapshot`]: struct.Snapshot.html
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot::new(&*self.volume, &self.space.index, &self.space.expiry, self.verify)
+    }
+
+    /// Reclaims the space used by removed and overwritten items.
+    ///
+    /// Every strand holding deleted items is sealed so that no new
+    /// items are written to it, and all of its live items are copied
+    /// into the remaining strands. Once it is empty, the strand is
+    /// trimmed and made available for writing again.
+    ///
+    /// Items whose time-to-live has passed are removed first,
+    /// so that their space is reclaimed as well.
+    ///
+    /// Other operations on the datastore may run concurrently with
+    /// this method, though lookups of items that are being moved
+    /// will wait for them to be relocated.
+    pub fn vacuum(&self) -> Result<()> {
+        let vacuum = Vacuum {
+            volume: &*self.volume,
+            spaces: &*self.spaces,
+            deleted: &*self.deleted,
+            cache: &*self.cache,
+        };
+
+        vacuum.run()
+    }
+
+    /// Saves the current state of the indexer and deleted items to disk.
+    ///
+    /// The volume header is then updated to point to it, so that the
+    /// next open only has to replay items written after this call.
+    /// Changes are blocked while the in-memory index is copied, but
+    /// may continue while the copy is serialized and written.
+    pub fn checkpoint(&self) -> Result<()> {
+        let checkpoint = Checkpoint {
+            volume: &*self.volume,
+            spaces: &*self.spaces,
+            deleted: &*self.deleted,
+        };
+
+        checkpoint.run()
+    }
+
+    /// Closes the datastore.
+    ///
+    /// This stops any background threads, saves a final checkpoint,
+    /// and writes the header of every strand. Unlike dropping the
+    /// handle, any error that occurs is returned to the caller.
+    pub fn close(mut self) -> Result<()> {
+        self.closed = true;
+        self.shutdown()
+    }
+
+    /// If the datastore was opened with [`OpenOptions::reindex`], returns
+    /// a summary of the items that were recovered from disk.
+    ///
+    /// [`OpenOptions::reindex`]: struct.OpenOptions.html#structfield.reindex
+    #[inline]
+    pub fn reindex_report(&self) -> Option<&ReindexReport> {
+        self.report.as_ref()
     }
 
     /// Retrieves statistics about the current state of the datastore.
     /// See [`Stats`] for more information about each field.
     ///
+    /// The read cache is shared by every keyspace, so its
+    /// counters cover reads made through any of them.
+    ///
     /// [`Stats`]: struct.Stats.html
-    #[inline]
     pub fn stats(&self) -> Stats {
-        self.volume.stats()
+        let mut stats = self.volume.stats();
+        stats += self.cache.stats();
+        stats
     }
 
     // Helpers
+    fn verify_name(name: &str) -> Result<()> {
+        if name.is_empty() || name.len() > MAX_KEY_LEN {
+            Err(Error::BadArgument("Keyspace name is empty or too long."))
+        } else {
+            Ok(())
+        }
+    }
+
+    // A handle sharing everything with this one, except for the keyspace.
+    // Whether reads fill the cache is kept as well.
+    // It is marked as closed, since only the original may shut down.
+    fn with_space(&self, space: Arc<Space>) -> Self {
+        Store {
+            volume: self.volume.clone(),
+            space: space,
+            spaces: self.spaces.clone(),
+            deleted: self.deleted.clone(),
+            cache: self.cache.clone(),
+            worker: None,
+            checkpointer: None,
+            report: None,
+            verify: self.verify,
+            fill_cache: self.fill_cache,
+            closed: true,
+        }
+    }
+
+    // Locks the key's entry, failing if the keyspace has been dropped.
+    // A keyspace is only dropped while its index is frozen, so this
+    // can't change while the entry is held.
+    fn lock<'s, 'k>(&'s self, key: &'k [u8]) -> Result<IndexEntryGuard<'s, 'k>> {
+        let entry = self.space.index.lock(key);
+        if self.space.dropped() {
+            return Err(Error::KeyspaceNotFound);
+        }
+
+        Ok(entry)
+    }
+
+    fn lock_all<'s, 'k>(&'s self, keys: &[&'k [u8]]) -> Result<Vec<IndexEntryGuard<'s, 'k>>> {
+        let entries = self.space.index.lock_all(keys);
+        if self.space.dropped() {
+            return Err(Error::KeyspaceNotFound);
+        }
+
+        Ok(entries)
+    }
+
     fn lookup_item(&self, strand: &Strand, ptr: FilePointer, buf: &mut [u8]) -> Result<usize> {
-        read_item(strand, ptr, |ctx| {
-            let key = ctx.key()?;
-            let val = ctx.val()?;
-            self.cache.insert(key, val);
-            ctx.copy_val(buf)
+        read_item(strand, ptr, self.verify, |ctx| {
+            match self.cache.fill(self.fill_cache, self.space.id(), &ctx)? {
+                Some(val) => {
+                    let len = min(buf.len(), val.len());
+                    buf[..len].copy_from_slice(&val[..len]);
+                    Ok(len)
+                }
+                None => ctx.copy_val(buf),
+            }
+        })
+    }
+
+    fn verify_batch(batch: &WriteBatch) -> Result<()> {
+        for (key, op) in batch.iter() {
+            Self::verify_key(key)?;
+
+            match *op {
+                Operation::Put(ref val) |
+                Operation::Insert(ref val) => Self::verify_val(val)?,
+                Operation::Remove => (),
+            }
+        }
+
+        Ok(())
+    }
+
+    // The entries must be sorted by key, and include every key in the batch
+    fn apply_batch(&self, batch: &WriteBatch, entries: &mut [IndexEntryGuard]) -> Result<()> {
+        let mut changes = Vec::with_capacity(batch.len());
+        for (key, op) in batch.iter() {
+            let i = entries
+                .binary_search_by(|entry| entry.key().cmp(&**key))
+                .expect("Batch item is not locked");
+
+            let exists = self.live(&entries[i]).is_some();
+            let val = match *op {
+                Operation::Insert(_) if exists => return Err(Error::ItemExists),
+                Operation::Put(ref val) |
+                Operation::Insert(ref val) => Some(val.as_slice()),
+                Operation::Remove if exists => None,
+                Operation::Remove => continue,
+            };
+
+            changes.push((i, &**key, val));
+        }
+
+        if changes.is_empty() {
+            return Ok(());
+        }
+
+        let ptrs = self.volume.write(|strand| {
+            let records = changes
+                .iter()
+                .map(|&(_, key, val)| {
+                    Record {
+                        keyspace: self.space.id(),
+                        key: key,
+                        val: val,
+                        sequence: strand.next_sequence(),
+                        expires: None,
+                    }
+                })
+                .collect::<Vec<_>>();
+
+            write_records(strand, &records)
+        })?;
+
+        for (&(i, key, val), ptr) in changes.iter().zip(ptrs) {
+            let entry = &mut entries[i];
+            if let Some(old_ptr) = entry.value {
+                self.remove_item(key, old_ptr);
+            }
+
+            // Removals are recorded with tombstones, like in bury_item()
+            entry.value = match val {
+                Some(_) => Some(ptr),
+                None => {
+                    self.deleted.add(ptr);
+                    None
+                }
+            };
+        }
+
+        Ok(())
+    }
+
+    // Reads an item along with what is needed to tell if it changes later.
+    // The strand generation is included in case the pointer is reused.
+    pub(crate) fn read_version(&self, key: &[u8]) -> Result<(Option<Vec<u8>>, Version)> {
+        Self::verify_key(key)?;
+
+        let entry = self.lock(key)?;
+        let ptr = match entry.value {
+            Some(ptr) => ptr,
+            None => return Ok((None, None)),
+        };
+
+        self.volume.read(ptr, |strand| {
+            let version = Some((ptr, strand.generation()));
+
+            // Expired items are only read as missing, the version still applies
+            if self.space.expiry.expired(key) {
+                return Ok((None, version));
+            }
+
+            let val = read_item(strand, ptr, self.verify, |ctx| Ok(Vec::from(ctx.val()?)))?;
+            Ok((Some(val), version))
         })
     }
 
+    // Applies the writes, as long as none of the reads have changed
+    pub(crate) fn commit(
+        &self,
+        reads: &BTreeMap<Box<[u8]>, Version>,
+        writes: &WriteBatch,
+    ) -> Result<()> {
+        Self::verify_batch(writes)?;
+
+        let mut keys = reads
+            .keys()
+            .chain(writes.iter().map(|(key, _)| key))
+            .map(|key| &**key)
+            .collect::<Vec<_>>();
+        keys.sort();
+        keys.dedup();
+
+        let mut entries = self.lock_all(&keys)?;
+
+        for (key, version) in reads {
+            let i = entries
+                .binary_search_by(|entry| entry.key().cmp(&**key))
+                .expect("Read item is not locked");
+
+            let current = entries[i].value.map(|ptr| {
+                (ptr, self.volume.read(ptr, |strand| strand.generation()))
+            });
+
+            if current != *version {
+                return Err(Error::Conflict);
+            }
+        }
+
+        self.apply_batch(writes, &mut entries)
+    }
+
+    // Reads that opted out of caching don't count as uses of the key
+    fn cached(&self, key: &[u8]) -> Option<ValueRef> {
+        if self.fill_cache {
+            self.cache.get_ref(self.space.id(), key)
+        } else {
+            self.cache.peek(self.space.id(), key)
+        }
+    }
+
+    // Returns the entry's item, unless it doesn't exist or has expired
+    fn live(&self, entry: &IndexEntryGuard) -> Option<FilePointer> {
+        match entry.value {
+            Some(ptr) if !self.space.expiry.expired(entry.key()) => Some(ptr),
+            _ => None,
+        }
+    }
+
     fn remove_item(&self, key: &[u8], ptr: FilePointer) {
-        self.cache.remove(key);
+        self.volume.read(ptr, |strand| strand.mark_deleted());
+
+        self.cache.remove(self.space.id(), key);
+        self.space.expiry.clear(key);
         self.deleted.add(ptr);
     }
 
-    fn write_state(&mut self) -> Result<()> {
-        let index = self.index.get_mut();
-        let deleted = self.deleted.get_mut();
+    fn shutdown(&mut self) -> Result<()> {
+        // The workers must not be touching the volume while we write
+        self.worker = None;
+        self.checkpointer = None;
 
-        self.volume.write(|strand| {
-            let state = DatastoreState::new(index, deleted)?;
-            state.write(strand)
-        })
+        self.checkpoint()?;
+        self.volume.write_metadata()
+    }
+
+    // Records the removal of a key on disk, so that
+    // it isn't brought back if the index is rebuilt.
+    fn bury_item(&self, key: &[u8]) -> Result<()> {
+        let ptr = self.volume.write(|strand| write_tombstone(strand, self.space.id(), key))?;
+        self.deleted.add(ptr);
+        Ok(())
     }
 }
 
-impl<'a> Drop for Store<'a> {
+impl Drop for Store {
     fn drop(&mut self) {
-        self.write_state().expect("Writing datastore state failed");
+        if self.closed {
+            return;
+        }
+
+        // Panicking here could abort the process during unwinding
+        if let Err(err) = self.shutdown() {
+            eprintln!("Error closing datastore: {}", err);
+        }
     }
 }
 
-unsafe impl<'a> Send for Store<'a> {}
-unsafe impl<'a> Sync for Store<'a> {}
+unsafe impl Send for Store {}
+unsafe impl Sync for Store {}
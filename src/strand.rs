@@ -25,6 +25,9 @@ use device::Device;
 use parking_lot::Mutex;
 use serial::StrandHeader;
 use stats::Stats;
+use std::cmp::max;
+use std::sync::Arc;
+use utils::{block_align, block_align_up};
 
 #[derive(Debug)]
 pub struct Strand<'d> {
@@ -33,6 +36,11 @@ pub struct Strand<'d> {
     start: u64,
     capacity: u64,
     offset: u64,
+    generation: u64,
+    sealed: bool,
+    sequence: Arc<Mutex<u64>>,
+    min_sequence: u64,
+    max_sequence: u64,
     pub stats: Mutex<Stats>,
 }
 
@@ -43,6 +51,7 @@ impl<'d> Strand<'d> {
         start: u64,
         capacity: u64,
         read_strand: bool,
+        sequence: Arc<Mutex<u64>>,
     ) -> Result<Self> {
         assert_eq!(
             start % PAGE_SIZE64,
@@ -60,31 +69,48 @@ impl<'d> Strand<'d> {
         );
         assert!(capacity > PAGE_SIZE64, "Strand only one page long");
 
-        let offset = {
+        let (offset, generation, sequences, stats) = {
             let mut page = Page::default();
 
             if read_strand {
                 // Read existing header
-                device.read(0, &mut page[..])?;
+                device.read(start, &mut page[..])?;
                 let header = StrandHeader::read(&page)?;
-                header.get_offset()
+                (
+                    header.get_offset(),
+                    header.get_generation(),
+                    header.get_sequences(),
+                    header.get_stats(),
+                )
             } else {
                 // Format strand
                 let header = StrandHeader::new(id, capacity);
                 header.write(&mut page)?;
-                device.write(0, &page[..])?;
+                device.write(start, &page[..])?;
 
-                PAGE_SIZE64
+                (PAGE_SIZE64, 0, (0, 0), Stats::default())
             }
         };
 
+        // Continue numbering after every item written so far
+        let (min_sequence, max_sequence) = sequences;
+        {
+            let mut sequence = sequence.lock();
+            *sequence = max(*sequence, max_sequence);
+        }
+
         Ok(Strand {
             device: device,
             id: id,
             start: start,
             capacity: capacity,
             offset: offset,
-            stats: Mutex::new(Stats::default()),
+            generation: generation,
+            sealed: false,
+            sequence: sequence,
+            min_sequence: min_sequence,
+            max_sequence: max_sequence,
+            stats: Mutex::new(stats),
         })
     }
 
@@ -123,6 +149,56 @@ impl<'d> Strand<'d> {
         self.offset += amt;
     }
 
+    // Moves the offset back to before a write that failed
+    #[inline]
+    pub fn rewind(&mut self, offset: u64) {
+        debug_assert!(offset <= self.offset, "Rewinding past the offset");
+        self.offset = offset;
+    }
+
+    #[inline]
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    #[inline]
+    pub fn sequences(&self) -> (u64, u64) {
+        (self.min_sequence, self.max_sequence)
+    }
+
+    // Sequence numbers are shared between all strands in the volume
+    pub fn next_sequence(&self) -> u64 {
+        let mut sequence = self.sequence.lock();
+        *sequence += 1;
+        *sequence
+    }
+
+    // Tracks that an item with this sequence number is now in the strand
+    pub fn add_sequence(&mut self, sequence: u64) {
+        if self.min_sequence == 0 || sequence < self.min_sequence {
+            self.min_sequence = sequence;
+        }
+
+        self.max_sequence = max(self.max_sequence, sequence);
+    }
+
+    #[inline]
+    pub fn sealed(&self) -> bool {
+        self.sealed
+    }
+
+    #[inline]
+    pub fn set_sealed(&mut self, sealed: bool) {
+        self.sealed = sealed;
+    }
+
+    // Records that an item in this strand is no longer live
+    pub fn mark_deleted(&self) {
+        let stats = &mut self.stats.lock();
+        stats.valid_items = stats.valid_items.saturating_sub(1);
+        stats.deleted_items += 1;
+    }
+
     #[inline]
     pub fn contains_ptr(&self, ptr: FilePointer) -> bool {
         self.start <= ptr && ptr <= self.end()
@@ -167,7 +243,6 @@ impl<'d> Strand<'d> {
         self.device.write(self.start + off, buf)
     }
 
-    #[allow(unused)]
     pub fn trim(&self, off: u64, len: u64) -> Result<()> {
         debug_assert!(off < self.capacity, "Offset is outside strand");
         debug_assert!(
@@ -182,10 +257,36 @@ impl<'d> Strand<'d> {
 
         self.device.trim(self.start + off, len)
     }
+
+    // Discards everything written to this strand, making
+    // all of its space available for writing again. The
+    // caller must ensure nothing still points into it.
+    pub fn reset(&mut self) -> Result<()> {
+        // Trims must be block-aligned on the device
+        let first = block_align_up(self.start + PAGE_SIZE64);
+        let last = block_align(self.start + self.offset);
+        if first < last {
+            self.trim(first - self.start, last - first)?;
+        }
+
+        self.offset = PAGE_SIZE64;
+        self.generation += 1;
+        self.min_sequence = 0;
+
+        {
+            let stats = self.stats.get_mut();
+            stats.valid_items = 0;
+            stats.deleted_items = 0;
+        }
+
+        self.write_metadata()
+    }
 }
 
 impl<'d> Drop for Strand<'d> {
     fn drop(&mut self) {
-        self.write_metadata().expect("Error writing metadata");
+        if let Err(err) = self.write_metadata() {
+            eprintln!("Error writing metadata for strand {}: {}", self.id, err);
+        }
     }
 }
@@ -0,0 +1,109 @@
+/*
+ * transaction.rs
+ *
+ * striking-db - Persistent key/value store for SSDs.
+ * Copyright (c) 2017 Maxwell Duzen, Ammon Smith
+ *
+ * striking-db is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Lesser General Public License as
+ * published by the Free Software Foundation, either version 2 of
+ * the License, or (at your option) any later version.
+ *
+ * striking-db is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public
+ * License along with striking-db.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+use super::{FilePointer, Result};
+use batch::{Operation, WriteBatch};
+use std::collections::BTreeMap;
+use store::Store;
+
+// The location of an item when it was read, along with the generation
+// of its strand. None if the item didn't exist.
+pub type Version = Option<(FilePointer, u64)>;
+
+/// A read-modify-write transaction over several items.
+/// See [`Store::transaction`].
+///
+/// Reads go to the datastore, but see any changes made earlier in the
+/// same transaction. Changes are held here until the transaction is
+/// committed.
+///
+/// [`Store::transaction`]: struct.Store.html#method.transaction
+#[derive(Debug)]
+pub struct Transaction<'s> {
+    store: &'s Store,
+    reads: BTreeMap<Box<[u8]>, Version>,
+    writes: WriteBatch,
+}
+
+impl<'s> Transaction<'s> {
+    pub(crate) fn new(store: &'s Store) -> Self {
+        Transaction {
+            store: store,
+            reads: BTreeMap::new(),
+            writes: WriteBatch::new(),
+        }
+    }
+
+    /// Gets the value of the given item, or `None` if it doesn't exist.
+    ///
+    /// The item is checked for changes by others when the transaction
+    /// is committed. Values that are too long to be read into memory
+    /// are returned as an error, like they are by [`Store::get`].
+    ///
+    /// [`Store::get`]: struct.Store.html#method.get
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        if let Some(op) = self.writes.get(key) {
+            return Ok(match *op {
+                Operation::Put(ref val) |
+                Operation::Insert(ref val) => Some(val.clone()),
+                Operation::Remove => None,
+            });
+        }
+
+        let (val, version) = self.store.read_version(key)?;
+
+        // Only the first read matters, any changes
+        // after it will cause a conflict anyways.
+        if !self.reads.contains_key(key) {
+            let key = Vec::from(key).into_boxed_slice();
+            self.reads.insert(key, version);
+        }
+
+        Ok(val)
+    }
+
+    /// Puts an item in the datastore when the transaction
+    /// is committed. See [`Store::put`].
+    ///
+    /// [`Store::put`]: struct.Store.html#method.put
+    pub fn put(&mut self, key: &[u8], val: &[u8]) -> Result<()> {
+        Store::verify_key(key)?;
+        Store::verify_val(val)?;
+
+        self.writes.put(key, val);
+        Ok(())
+    }
+
+    /// Removes an item from the datastore when the transaction
+    /// is committed. See [`Store::remove`].
+    ///
+    /// [`Store::remove`]: struct.Store.html#method.remove
+    pub fn remove(&mut self, key: &[u8]) -> Result<()> {
+        Store::verify_key(key)?;
+
+        self.writes.remove(key);
+        Ok(())
+    }
+
+    pub(crate) fn commit(self) -> Result<()> {
+        self.store.commit(&self.reads, &self.writes)
+    }
+}
@@ -0,0 +1,353 @@
+/*
+ * typed/mod.rs
+ *
+ * striking-db - Persistent key/value store for SSDs.
+ * Copyright (c) 2017 Maxwell Duzen, Ammon Smith
+ *
+ * striking-db is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Lesser General Public License as
+ * published by the Free Software Foundation, either version 2 of
+ * the License, or (at your option) any later version.
+ *
+ * striking-db is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public
+ * License along with striking-db.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+
+mod de;
+mod ser;
+
+use error::{Error, Result};
+use iter::Range;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use std::collections::Bound;
+use std::collections::Bound::*;
+use std::marker::PhantomData;
+use std::time::Duration;
+use store::Store;
+
+/// Encodes and decodes the values of a [`TypedStore`].
+///
+/// Implement this to store values in a format of your choosing, such as
+/// JSON or bincode. Any errors should be converted into [`Error::Codec`].
+/// Keys are always encoded with [`Ordered`], so that they keep their order.
+///
+/// [`TypedStore`]: struct.TypedStore.html
+/// [`Error::Codec`]: enum.Error.html
+/// [`Ordered`]: struct.Ordered.html
+pub trait Codec {
+    /// Encodes the value as bytes.
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<Vec<u8>>;
+
+    /// Decodes a value from the given bytes.
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T>;
+}
+
+/// An encoding whose output sorts in the same order as the values it encodes.
+///
+/// Integers, floats, strings, sequences, tuples, structs and enums are all
+/// ordered as they are in Rust, when compared with [`PartialOrd`]. Enum
+/// variants are ordered by their declaration. The encoding does not describe
+/// the types of its contents, so it can only be decoded as the type it was
+/// encoded from. This is the default codec for values in a [`TypedStore`].
+///
+/// [`PartialOrd`]: https://doc.rust-lang.org/stable/std/cmp/trait.PartialOrd.html
+/// [`TypedStore`]: struct.TypedStore.html
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Ordered;
+
+impl Codec for Ordered {
+    #[inline]
+    fn encode<T: Serialize + ?Sized>(&self, value: &T) -> Result<Vec<u8>> {
+        ser::to_bytes(value)
+    }
+
+    #[inline]
+    fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T> {
+        de::from_bytes(bytes)
+    }
+}
+
+/// A view of a [`Store`] whose keys and values are Rust types.
+///
+/// Keys are encoded with [`Ordered`], so iterating over a range of them
+/// visits them in the same order as their types would sort. Values are
+/// encoded with the codec `C`. Errors while encoding or decoding either
+/// are returned as [`Error::Codec`]. Since it only borrows the store, a
+/// typed view may also be made of a [`Keyspace`].
+///
+/// This is only available with the `serde` feature.
+///
+/// [`Store`]: struct.Store.html
+/// [`Keyspace`]: struct.Keyspace.html
+/// [`Ordered`]: struct.Ordered.html
+/// [`Error::Codec`]: enum.Error.html
+#[derive(Debug)]
+pub struct TypedStore<'s, K, V, C = Ordered> {
+    store: &'s Store,
+    codec: C,
+    phantom: PhantomData<(K, V)>,
+}
+
+impl<'s, K, V> TypedStore<'s, K, V, Ordered>
+where
+    K: Serialize + DeserializeOwned,
+    V: Serialize + DeserializeOwned,
+{
+    /// Creates a typed view of the store, using [`Ordered`] for values.
+    ///
+    /// [`Ordered`]: struct.Ordered.html
+    pub fn new(store: &'s Store) -> Self {
+        Self::with_codec(store, Ordered)
+    }
+}
+
+impl<'s, K, V, C> TypedStore<'s, K, V, C>
+where
+    K: Serialize + DeserializeOwned,
+    V: Serialize + DeserializeOwned,
+    C: Codec,
+{
+    /// Creates a typed view of the store, using the given codec for values.
+    pub fn with_codec(store: &'s Store, codec: C) -> Self {
+        TypedStore {
+            store: store,
+            codec: codec,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Returns the underlying store.
+    #[inline]
+    pub fn store(&self) -> &'s Store {
+        self.store
+    }
+
+    /// Gets the value of the given item. See [`Store::get`].
+    ///
+    /// [`Store::get`]: struct.Store.html#method.get
+    pub fn get(&self, key: &K) -> Result<V> {
+        let key = ser::to_bytes(key)?;
+        let val = self.store.get_ref(&key)?;
+        self.codec.decode(&val)
+    }
+
+    /// Checks if the given item exists. See [`Store::exists`].
+    ///
+    /// [`Store::exists`]: struct.Store.html#method.exists
+    pub fn exists(&self, key: &K) -> Result<bool> {
+        let key = ser::to_bytes(key)?;
+        Ok(self.store.exists(&key))
+    }
+
+    /// Inserts an item. See [`Store::insert`].
+    ///
+    /// [`Store::insert`]: struct.Store.html#method.insert
+    pub fn insert(&self, key: &K, val: &V) -> Result<()> {
+        let key = ser::to_bytes(key)?;
+        let val = self.codec.encode(val)?;
+        self.store.insert(&key, &val)
+    }
+
+    /// Updates an item. See [`Store::update`].
+    ///
+    /// [`Store::update`]: struct.Store.html#method.update
+    pub fn update(&self, key: &K, val: &V) -> Result<()> {
+        let key = ser::to_bytes(key)?;
+        let val = self.codec.encode(val)?;
+        self.store.update(&key, &val)
+    }
+
+    /// Puts an item. See [`Store::put`].
+    ///
+    /// [`Store::put`]: struct.Store.html#method.put
+    pub fn put(&self, key: &K, val: &V) -> Result<()> {
+        let key = ser::to_bytes(key)?;
+        let val = self.codec.encode(val)?;
+        self.store.put(&key, &val)
+    }
+
+    /// Puts an item that expires after the given duration.
+    /// See [`Store::put_with_ttl`].
+    ///
+    /// [`Store::put_with_ttl`]: struct.Store.html#method.put_with_ttl
+    pub fn put_with_ttl(&self, key: &K, val: &V, ttl: Duration) -> Result<()> {
+        let key = ser::to_bytes(key)?;
+        let val = self.codec.encode(val)?;
+        self.store.put_with_ttl(&key, &val, ttl)
+    }
+
+    /// Removes an item. See [`Store::remove`].
+    ///
+    /// [`Store::remove`]: struct.Store.html#method.remove
+    pub fn remove(&self, key: &K) -> Result<()> {
+        let key = ser::to_bytes(key)?;
+        self.store.remove(&key)
+    }
+
+    /// Removes an item, returning its value. If there is no such
+    /// item, then [`Error::ItemNotFound`] is returned. If its value
+    /// can't be decoded, then the item is left in place.
+    ///
+    /// [`Error::ItemNotFound`]: enum.Error.html
+    pub fn delete(&self, key: &K) -> Result<V> {
+        let key = ser::to_bytes(key)?;
+
+        // Nothing is removed unless the old value can be returned
+        self.store.try_merge::<_, V, Error>(&key, |val| match val {
+            Some(val) => Ok((None, self.codec.decode(&val)?)),
+            None => Err(Error::ItemNotFound),
+        })
+    }
+
+    /// Changes an item, but only if its current value is as expected.
+    /// Values are compared by their encodings. See [`Store::compare_and_swap`].
+    ///
+    /// [`Store::compare_and_swap`]: struct.Store.html#method.compare_and_swap
+    pub fn compare_and_swap(&self, key: &K, expected: Option<&V>, new: Option<&V>) -> Result<()> {
+        let key = ser::to_bytes(key)?;
+        let expected = self.encode_opt(expected)?;
+        let new = self.encode_opt(new)?;
+
+        self.store.compare_and_swap(
+            &key,
+            expected.as_ref().map(|val| val.as_slice()),
+            new.as_ref().map(|val| val.as_slice()),
+        )
+    }
+
+    /// Performs an atomic read-modify-write on the given item.
+    /// See [`Store::merge`].
+    ///
+    /// [`Store::merge`]: struct.Store.html#method.merge
+    pub fn merge<F>(&self, key: &K, func: F) -> Result<()>
+    where
+        F: FnOnce(Option<V>) -> Option<V>,
+    {
+        let key = ser::to_bytes(key)?;
+
+        self.store.try_merge::<_, _, Error>(&key, |val| {
+            let val = match val {
+                Some(val) => Some(self.codec.decode(&val)?),
+                None => None,
+            };
+
+            Ok((self.encode_opt(func(val).as_ref())?, ()))
+        })
+    }
+
+    /// Iterates over the items with keys between the given bounds, in key order.
+    /// See [`Store::range`].
+    ///
+    /// [`Store::range`]: struct.Store.html#method.range
+    pub fn range(&self, start: Bound<&K>, end: Bound<&K>) -> Result<TypedRange<K, V, C>> {
+        let start = encode_bound(start)?;
+        let end = encode_bound(end)?;
+        let range = self.store.range((as_slice(&start), as_slice(&end)));
+
+        Ok(TypedRange::new(range, &self.codec))
+    }
+
+    /// Iterates over every item, in key order.
+    pub fn iter(&self) -> TypedRange<K, V, C> {
+        TypedRange::new(self.store.range(..), &self.codec)
+    }
+
+    /// Iterates over the items whose keys begin with the given prefix.
+    ///
+    /// The prefix is encoded like a key, so for keys that are tuples,
+    /// passing the first field finds every key starting with it.
+    pub fn scan_prefix<P: Serialize + ?Sized>(&self, prefix: &P) -> Result<TypedRange<K, V, C>> {
+        let prefix = ser::to_bytes(prefix)?;
+        let range = self.store.scan_prefix(&prefix);
+
+        Ok(TypedRange::new(range, &self.codec))
+    }
+
+    fn encode_opt(&self, val: Option<&V>) -> Result<Option<Vec<u8>>> {
+        match val {
+            Some(val) => self.codec.encode(val).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+fn encode_bound<K: Serialize>(bound: Bound<&K>) -> Result<Bound<Vec<u8>>> {
+    Ok(match bound {
+        Included(key) => Included(ser::to_bytes(key)?),
+        Excluded(key) => Excluded(ser::to_bytes(key)?),
+        Unbounded => Unbounded,
+    })
+}
+
+fn as_slice(bound: &Bound<Vec<u8>>) -> Bound<&[u8]> {
+    match *bound {
+        Included(ref key) => Included(key.as_slice()),
+        Excluded(ref key) => Excluded(key.as_slice()),
+        Unbounded => Unbounded,
+    }
+}
+
+/// An iterator over a range of items in a [`TypedStore`], in key order.
+/// It yields the decoded key and value of each item.
+///
+/// [`TypedStore`]: struct.TypedStore.html
+#[derive(Debug)]
+pub struct TypedRange<'t, K, V, C: 't> {
+    inner: Range<'t>,
+    codec: &'t C,
+    phantom: PhantomData<(K, V)>,
+}
+
+impl<'t, K, V, C> TypedRange<'t, K, V, C>
+where
+    K: DeserializeOwned,
+    V: DeserializeOwned,
+    C: Codec,
+{
+    fn new(inner: Range<'t>, codec: &'t C) -> Self {
+        TypedRange {
+            inner: inner,
+            codec: codec,
+            phantom: PhantomData,
+        }
+    }
+
+    fn decode(&self, item: Result<(Vec<u8>, Vec<u8>)>) -> Result<(K, V)> {
+        let (key, val) = item?;
+        Ok((de::from_bytes(&key)?, self.codec.decode(&val)?))
+    }
+}
+
+impl<'t, K, V, C> Iterator for TypedRange<'t, K, V, C>
+where
+    K: DeserializeOwned,
+    V: DeserializeOwned,
+    C: Codec,
+{
+    type Item = Result<(K, V)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let item = self.inner.next();
+        item.map(|item| self.decode(item))
+    }
+}
+
+impl<'t, K, V, C> DoubleEndedIterator for TypedRange<'t, K, V, C>
+where
+    K: DeserializeOwned,
+    V: DeserializeOwned,
+    C: Codec,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        let item = self.inner.next_back();
+        item.map(|item| self.decode(item))
+    }
+}
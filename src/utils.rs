@@ -30,3 +30,8 @@ pub fn align(off: u64) -> u64 {
 pub fn block_align(off: u64) -> u64 {
     (off / TRIM_SIZE64) * TRIM_SIZE64
 }
+
+#[inline]
+pub fn block_align_up(off: u64) -> u64 {
+    block_align(off + TRIM_SIZE64 - 1)
+}
@@ -0,0 +1,412 @@
+/*
+ * vacuum.rs
+ *
+ * striking-db - Persistent key/value store for SSDs.
+ * Copyright (c) 2017 Maxwell Duzen, Ammon Smith
+ *
+ * striking-db is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Lesser General Public License as
+ * published by the Free Software Foundation, either version 2 of
+ * the License, or (at your option) any later version.
+ *
+ * striking-db is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public
+ * License along with striking-db.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+use super::{FilePointer, Result};
+use cache::ReadCache;
+use deleted::Deleted;
+use error::Error;
+use keyspace::{Space, Spaces};
+use options::VacuumOptions;
+use serial::{BlobReader, read_item, write_blob, write_record, write_tombstone};
+use std::cmp::max;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use volume::Volume;
+use worker::{Signal, Worker};
+
+#[derive(Debug, Copy, Clone)]
+pub struct Vacuum<'s> {
+    pub volume: &'s Volume<'static>,
+    pub spaces: &'s Spaces,
+    pub deleted: &'s Deleted,
+    pub cache: &'s ReadCache,
+}
+
+impl<'s> Vacuum<'s> {
+    pub fn run(&self) -> Result<()> {
+        self.expire()?;
+
+        for id in 0..self.volume.strand_count() {
+            let deleted = self.volume.strand(
+                id,
+                |strand| strand.stats.lock().deleted_items,
+            );
+
+            if deleted > 0 {
+                self.strand(id, None)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    // Removes every item whose time-to-live has passed. A tombstone is
+    // written for each, so that older copies stay removed on reindexing.
+    pub fn expire(&self) -> Result<()> {
+        for space in self.spaces.all() {
+            self.expire_space(&space)?;
+        }
+
+        Ok(())
+    }
+
+    fn expire_space(&self, space: &Space) -> Result<()> {
+        for key in space.expiry.due() {
+            let mut entry = space.index.lock(&key);
+
+            // The item may have been replaced since the keys were gathered,
+            // or the whole keyspace dropped
+            let ptr = match entry.value {
+                Some(ptr) if space.expiry.expired(&key) && !space.dropped() => ptr,
+                _ => continue,
+            };
+
+            let tombstone = self.volume.write(
+                |strand| write_tombstone(strand, space.id(), &key),
+            )?;
+            self.deleted.add(tombstone);
+
+            self.volume.read(ptr, |strand| strand.mark_deleted());
+            self.deleted.add(ptr);
+            self.cache.remove(space.id(), &key);
+            space.expiry.clear(&key);
+            entry.value = None;
+        }
+
+        Ok(())
+    }
+
+    // Checks if the strand's ratio of deleted to valid items,
+    // as a percentage, has exceeded the given threshold.
+    pub fn needed(&self, id: u16, threshold: u32) -> bool {
+        self.volume.strand(id, |strand| {
+            let stats = strand.stats.lock();
+            let deleted = stats.deleted_items;
+            let valid = stats.valid_items;
+
+            deleted > 0 && deleted * 100 > valid * threshold as u64
+        })
+    }
+
+    // Returns false if the vacuum was interrupted before completion,
+    // or if the strand is still needed by a snapshot.
+    pub fn strand(&self, id: u16, throttle: Option<&mut Throttle>) -> Result<bool> {
+        if self.volume.pinned(id) {
+            return Ok(false);
+        }
+
+        // Already being vacuumed elsewhere, reserved for
+        // a write, or the only strand left to write to
+        if !self.volume.seal(id) {
+            return Ok(false);
+        }
+
+        let (start, end) = self.volume.strand(id, |strand| (strand.start(), strand.end()));
+
+        let result = self.relocate(start, end, throttle).and_then(|done| {
+            if !done {
+                return Ok(false);
+            }
+
+            self.keep_tombstones(start, end)?;
+
+            // A snapshot may have been taken while relocating
+            if self.volume.pinned(id) {
+                return Ok(false);
+            }
+
+            self.volume.forget_state(start, end)?;
+            self.volume.try_reset(id, |strand| {
+                self.deleted.remove_range(start, end);
+                strand.reset()
+            })
+        });
+
+        self.volume.unseal(id);
+        result
+    }
+
+    // Keyspaces added after the strand was sealed can't have anything
+    // in it, so only the ones that already exist need to be checked.
+    fn relocate(
+        &self,
+        start: FilePointer,
+        end: FilePointer,
+        mut throttle: Option<&mut Throttle>,
+    ) -> Result<bool> {
+        for space in self.spaces.all() {
+            if !self.relocate_space(&space, start, end, &mut throttle)? {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    fn relocate_space(
+        &self,
+        space: &Space,
+        start: FilePointer,
+        end: FilePointer,
+        throttle: &mut Option<&mut Throttle>,
+    ) -> Result<bool> {
+        for key in space.index.keys_in_range(start, end) {
+            let mut entry = space.index.lock(&key);
+
+            // The item may have been changed since the keys were gathered
+            let ptr = match entry.value {
+                Some(ptr) if start <= ptr && ptr < end && !space.dropped() => ptr,
+                _ => continue,
+            };
+
+            let (sequence, expires, blob) = self.volume.read(ptr, |strand| {
+                read_item(strand, ptr, true, |ctx| {
+                    Ok((ctx.sequence(), ctx.expires(), ctx.blob()))
+                })
+            })?;
+
+            // Keep the sequence number, so this doesn't look newer than it is
+            let (new_ptr, len) = match blob {
+                Some((blob_ptr, len)) => {
+                    let new_ptr = self.relocate_blob(
+                        space.id(),
+                        &key,
+                        ptr,
+                        blob_ptr,
+                        len,
+                        sequence,
+                        expires,
+                    )?;
+                    (new_ptr, len)
+                }
+                None => {
+                    // Always verified, so corruption isn't given a fresh checksum
+                    let val = self.volume.read(ptr, |strand| {
+                        read_item(strand, ptr, true, |ctx| Ok(Vec::from(ctx.val()?)))
+                    })?;
+
+                    let new_ptr = self.volume.write(|strand| {
+                        let val = Some(val.as_slice());
+                        write_record(strand, space.id(), &key, val, sequence, expires)
+                    })?;
+
+                    (new_ptr, val.len() as u64)
+                }
+            };
+
+            // The old copy is now garbage, so if we are interrupted
+            // it will be picked up by the next vacuum.
+            self.volume.read(ptr, |strand| strand.mark_deleted());
+            self.deleted.add(ptr);
+            entry.value = Some(new_ptr);
+
+            if let Some(ref mut throttle) = *throttle {
+                if !throttle.consume(key.len() as u64 + len) {
+                    return Ok(false);
+                }
+            }
+        }
+
+        Ok(true)
+    }
+
+    // Streamed values are copied a piece at a time, so that they don't
+    // have to fit in memory. The copy is checked against the original's
+    // checksum before it is committed.
+    fn relocate_blob(
+        &self,
+        keyspace: u32,
+        key: &[u8],
+        ptr: FilePointer,
+        blob_ptr: FilePointer,
+        len: u64,
+        sequence: u64,
+        expires: Option<u64>,
+    ) -> Result<FilePointer> {
+        self.volume.read(ptr, |source| {
+            let mut reader = BlobReader::new(source, ptr, blob_ptr, len, true);
+
+            // The source strand is sealed, so this is never written to it
+            self.volume.write(|strand| {
+                write_blob(strand, keyspace, key, &mut reader, len, sequence, expires)
+            })
+        })
+    }
+
+    // A tombstone can only be discarded once no older copies of its
+    // key remain on disk, otherwise reindexing would bring them back.
+    // Those in dropped keyspaces are never needed again. Records that
+    // fail their checksum are never brought back by reindexing either,
+    // so they are treated as garbage rather than stopping the vacuum.
+    fn keep_tombstones(&self, start: FilePointer, end: FilePointer) -> Result<()> {
+        let mut tombstones = BTreeMap::new();
+
+        for ptr in self.deleted.range(start, end) {
+            let result = self.volume.read(ptr, |strand| {
+                read_item(strand, ptr, true, |ctx| if ctx.tombstone() {
+                    Ok(Some((ctx.keyspace(), Vec::from(ctx.key()?), ctx.sequence())))
+                } else {
+                    Ok(None)
+                })
+            });
+
+            let tombstone = match result {
+                Ok(tombstone) => tombstone,
+                Err(Error::CorruptItem(_)) => continue,
+                Err(error) => return Err(error),
+            };
+
+            if let Some((keyspace, key, sequence)) = tombstone {
+                if self.spaces.get(keyspace).is_some() {
+                    let newest = tombstones.entry((keyspace, key)).or_insert(sequence);
+                    *newest = max(*newest, sequence);
+                }
+            }
+        }
+
+        if tombstones.is_empty() {
+            return Ok(());
+        }
+
+        // Copies in this strand are about to be trimmed, and live items
+        // are always newer than any tombstone of their key, so only the
+        // deleted items in other strands matter.
+        let mut needed = BTreeSet::new();
+        for ptr in self.deleted.outside(start, end) {
+            let result = self.volume.read(ptr, |strand| {
+                read_item(strand, ptr, true, |ctx| if ctx.tombstone() {
+                    Ok(None)
+                } else {
+                    Ok(Some((ctx.keyspace(), Vec::from(ctx.key()?), ctx.sequence())))
+                })
+            });
+
+            let older = match result {
+                Ok(Some(older)) => older,
+                Ok(None) => continue,
+
+                // Its strand may have been reset by another vacuum since
+                // the pointers were gathered, in which case it's gone
+                Err(_) if !self.deleted.contains(ptr) => continue,
+                Err(Error::CorruptItem(_)) => continue,
+                Err(error) => return Err(error),
+            };
+
+            let (keyspace, key, sequence) = older;
+            let id = (keyspace, key);
+            if tombstones.get(&id).map_or(false, |&newer| sequence < newer) {
+                needed.insert(id);
+            }
+        }
+
+        for (id, sequence) in tombstones {
+            if !needed.contains(&id) {
+                continue;
+            }
+
+            let (keyspace, key) = id;
+            let new_ptr = self.volume.write(
+                |strand| write_record(strand, keyspace, &key, None, sequence, None),
+            )?;
+
+            self.deleted.add(new_ptr);
+        }
+
+        Ok(())
+    }
+}
+
+// Limits how many bytes are relocated per second.
+pub struct Throttle<'a> {
+    signal: &'a Signal,
+    budget: u64,
+    used: u64,
+    start: Instant,
+}
+
+impl<'a> Throttle<'a> {
+    pub fn new(signal: &'a Signal, budget: u64) -> Self {
+        Throttle {
+            signal: signal,
+            budget: budget,
+            used: 0,
+            start: Instant::now(),
+        }
+    }
+
+    // Returns false if the worker has been told to stop.
+    pub fn consume(&mut self, bytes: u64) -> bool {
+        self.used += bytes;
+        if self.used < self.budget {
+            return !self.signal.stopped();
+        }
+
+        // Budget exhausted, wait out the rest of this second
+        let second = Duration::from_secs(1);
+        let elapsed = self.start.elapsed();
+        let stopped = if elapsed < second {
+            self.signal.sleep(second - elapsed)
+        } else {
+            self.signal.stopped()
+        };
+
+        self.used = 0;
+        self.start = Instant::now();
+        !stopped
+    }
+}
+
+// Runs in the background, vacuuming strands once enough of their items are deleted
+pub fn spawn_worker(
+    volume: Arc<Volume<'static>>,
+    spaces: Arc<Spaces>,
+    deleted: Arc<Deleted>,
+    cache: Arc<ReadCache>,
+    options: VacuumOptions,
+) -> Worker {
+    Worker::spawn(options.interval, move |signal| {
+        let vacuum = Vacuum {
+            volume: &*volume,
+            spaces: &*spaces,
+            deleted: &*deleted,
+            cache: &*cache,
+        };
+
+        // A failure doesn't stop the other strands from being vacuumed
+        let mut result = vacuum.expire();
+
+        for id in 0..volume.strand_count() {
+            if signal.stopped() {
+                break;
+            }
+
+            if !vacuum.needed(id, options.threshold) {
+                continue;
+            }
+
+            let mut throttle = Throttle::new(signal, options.io_budget);
+            result = result.and(vacuum.strand(id, Some(&mut throttle)).map(|_| ()));
+        }
+
+        result
+    })
+}
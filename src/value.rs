@@ -0,0 +1,148 @@
+/*
+ * value.rs
+ *
+ * striking-db - Persistent key/value store for SSDs.
+ * Copyright (c) 2017 Maxwell Duzen, Ammon Smith
+ *
+ * striking-db is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Lesser General Public License as
+ * published by the Free Software Foundation, either version 2 of
+ * the License, or (at your option) any later version.
+ *
+ * striking-db is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public
+ * License along with striking-db.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+
+use super::{FilePointer, Result};
+use serial::{BlobCursor, read_item};
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+use volume::Volume;
+
+/// A reader over the value of an item. See [`Store::open_value`].
+///
+/// Values written with [`Store::put_stream`] are read from disk as they
+/// are needed, so the memory used doesn't depend on their length. Other
+/// values are read into memory when the reader is created.
+///
+/// Large values are stored in chunks of 64 KiB, each with its own
+/// checksum. Every chunk that is read from is checked, wherever the
+/// reader seeks to, unless [`OpenOptions::skip_checksums`] is set. The
+/// strand holding the value is not vacuumed while the reader exists,
+/// so it should not be kept longer than needed.
+///
+/// [`Store::open_value`]: struct.Store.html#method.open_value
+/// [`Store::put_stream`]: struct.Store.html#method.put_stream
+/// [`OpenOptions::skip_checksums`]: struct.OpenOptions.html#structfield.skip_checksums
+#[derive(Debug)]
+pub struct ValueReader<'s> {
+    volume: &'s Volume<'static>,
+    source: Source,
+}
+
+#[derive(Debug)]
+enum Source {
+    Inline(Cursor<Vec<u8>>),
+    Blob(u16, BlobCursor),
+}
+
+impl<'s> ValueReader<'s> {
+    // The item must be locked, so that it isn't moved before its strand is pinned
+    pub(crate) fn new(volume: &'s Volume<'static>, ptr: FilePointer, verify: bool) -> Result<Self> {
+        let source = volume.read(ptr, |strand| {
+            read_item(strand, ptr, verify, |ctx| match ctx.blob() {
+                Some((blob_ptr, len)) => {
+                    let cursor = BlobCursor::new(ptr, blob_ptr, len, verify);
+                    Ok(Source::Blob(strand.id(), cursor))
+                }
+                None => Ok(Source::Inline(Cursor::new(Vec::from(ctx.val()?)))),
+            })
+        })?;
+
+        if let Source::Blob(strand, _) = source {
+            volume.pins()[strand as usize] += 1;
+        }
+
+        Ok(ValueReader {
+            volume: volume,
+            source: source,
+        })
+    }
+
+    /// Returns the length of the value, in bytes.
+    pub fn len(&self) -> u64 {
+        match self.source {
+            Source::Inline(ref cursor) => cursor.get_ref().len() as u64,
+            Source::Blob(_, ref cursor) => cursor.len(),
+        }
+    }
+
+    /// Returns true if the value is empty.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<'s> Read for ValueReader<'s> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let cursor = match self.source {
+            Source::Inline(ref mut cursor) => return cursor.read(buf),
+            Source::Blob(_, ref mut cursor) => cursor,
+        };
+
+        let ptr = cursor.ptr();
+        let len = self.volume.read(ptr, |strand| cursor.read(strand, buf))?;
+        Ok(len)
+    }
+}
+
+impl<'s> Seek for ValueReader<'s> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let cursor = match self.source {
+            Source::Inline(ref mut cursor) => return cursor.seek(pos),
+            Source::Blob(_, ref mut cursor) => cursor,
+        };
+
+        let new_pos = match pos {
+            SeekFrom::Start(off) => Some(off),
+            SeekFrom::End(off) => offset(cursor.len(), off),
+            SeekFrom::Current(off) => offset(cursor.pos(), off),
+        };
+
+        let new_pos = match new_pos {
+            Some(new_pos) => new_pos,
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Invalid seek to a negative or overflowing position",
+                ))
+            }
+        };
+
+        cursor.seek(new_pos);
+        Ok(new_pos)
+    }
+}
+
+impl<'s> Drop for ValueReader<'s> {
+    fn drop(&mut self) {
+        if let Source::Blob(strand, _) = self.source {
+            self.volume.pins()[strand as usize] -= 1;
+        }
+    }
+}
+
+fn offset(base: u64, off: i64) -> Option<u64> {
+    if off >= 0 {
+        base.checked_add(off as u64)
+    } else {
+        base.checked_sub(off.wrapping_neg() as u64)
+    }
+}
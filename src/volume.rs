@@ -22,20 +22,24 @@
 use self::rentals::VolumeRental;
 use super::{MIN_STRANDS, PAGE_SIZE64, FilePointer, Result};
 use buffer::Page;
-use deleted::Deleted;
+use deleted::{Deleted, DeletedSet};
 use device::Device;
 use error::Error;
-use index::Index;
+use keyspace::{CATALOG, DEFAULT_KEYSPACE, Spaces};
 use num_cpus;
 use options::OpenOptions;
-use parking_lot::RwLock;
-use serial::{DatastoreState, VolumeHeader};
-use stats::Stats;
+use parking_lot::{Mutex, MutexGuard, RwLock};
+use serial::{DatastoreState, SavedState, StrandPosition, VolumeHeader, read_item, scan_items};
+use stats::{ReindexReport, Stats};
 use std::cmp::{Ordering, min};
+use std::collections::BTreeMap;
+use std::mem;
+use std::sync::Arc;
+use std::thread;
 use std::time::Duration;
 use std::u16;
 use strand::Strand;
-use utils::align;
+use utils::{align, block_align};
 
 #[derive(Debug)]
 struct VolumeOpen {
@@ -100,29 +104,42 @@ rental! {
 }
 
 #[derive(Debug, Default)]
-pub struct VolumeState(Option<(Index, Deleted)>);
+pub struct VolumeState(Option<(Spaces, Deleted)>, Option<ReindexReport>);
 
 impl VolumeState {
-    pub fn new(index: Index, deleted: Deleted) -> Self {
-        VolumeState(Some((index, deleted)))
+    pub fn new(spaces: Spaces, deleted: Deleted) -> Self {
+        VolumeState(Some((spaces, deleted)), None)
     }
 
-    pub fn extract(self) -> (Index, Deleted) {
+    pub fn reindexed(spaces: Spaces, deleted: Deleted, report: ReindexReport) -> Self {
+        VolumeState(Some((spaces, deleted)), Some(report))
+    }
+
+    pub fn extract(self) -> (Spaces, Deleted, Option<ReindexReport>) {
         match self.0 {
-            Some((idx, del)) => (idx, del),
-            None => (Index::new(), Deleted::new()),
+            Some((spaces, del)) => (spaces, del, self.1),
+            None => (Spaces::new(), Deleted::new(), self.1),
         }
     }
 }
 
 #[derive(Debug)]
-pub struct Volume<'a>(VolumeRental<'a>);
+pub struct Volume<'a> {
+    rental: VolumeRental<'a>,
+    sequence: Arc<Mutex<u64>>,
+    state_ptr: Mutex<Option<FilePointer>>,
+    pins: Mutex<Vec<u32>>,
+
+    // How many strands are sealed, which is always fewer than all of them
+    sealed: Mutex<usize>,
+}
 
 impl<'a> Volume<'a> {
     pub fn open(device: Box<Device>, options: &OpenOptions) -> Result<(Self, VolumeState)> {
         use rental::TryNewError;
 
         let mut state_ptr = None;
+        let sequence = Arc::new(Mutex::new(0));
         let try_rental = VolumeRental::try_new(device, |device| {
             use OpenMode::*;
 
@@ -136,8 +153,16 @@ impl<'a> Volume<'a> {
                 state_ptr = open.state_ptr;
             }
 
+            // Any bytes past the last whole block can't be trimmed
             if options.mode == Truncate {
-                device.trim(0, device.capacity())?;
+                device.trim(0, block_align(device.capacity()))?;
+            }
+
+            if !open.read_disk {
+                let mut page = Page::default();
+                let header = VolumeHeader::new(open.strands, None);
+                header.write(&mut page)?;
+                device.write(0, &page[..])?;
             }
 
             // Divide device into strands
@@ -154,7 +179,7 @@ impl<'a> Volume<'a> {
                 debug_assert_ne!(len, 0, "Length of strand must be nonzero");
 
                 left -= len;
-                let strand = Strand::new(device, i, off, len, open.read_disk)?;
+                let strand = Strand::new(device, i, off, len, open.read_disk, sequence.clone())?;
                 strands.push(RwLock::new(strand));
             }
             debug_assert_eq!(left, 0, "Not all space is allocated in a strand");
@@ -162,16 +187,29 @@ impl<'a> Volume<'a> {
             Ok(strands.into_boxed_slice())
         });
 
-        if options.reindex {
-            return Err(Error::Unimplemented);
-        }
-
         match try_rental {
             Ok(rental) => {
-                let volume = Volume(rental);
-                let state = match state_ptr {
-                    Some(ptr) => volume.read(ptr, |strand| DatastoreState::read(strand, ptr))?,
-                    None => VolumeState::default(),
+                let count = rental.rent(|strands| strands.len());
+                let volume = Volume {
+                    rental: rental,
+                    sequence: sequence,
+                    state_ptr: Mutex::new(state_ptr),
+                    pins: Mutex::new(vec![0; count]),
+                    sealed: Mutex::new(0),
+                };
+                let saved = match state_ptr {
+                    Some(ptr) => Some(volume.read(
+                        ptr,
+                        |strand| DatastoreState::read(strand, ptr),
+                    )?),
+                    None => None,
+                };
+
+                let (spaces, deleted, report) = volume.replay(saved)?;
+                let state = if options.reindex {
+                    VolumeState::reindexed(spaces, deleted, report)
+                } else {
+                    VolumeState::new(spaces, deleted)
                 };
 
                 Ok((volume, state))
@@ -180,11 +218,250 @@ impl<'a> Volume<'a> {
         }
     }
 
+    // Applies every item written after the given state was saved.
+    // If there is no saved state, then the index is rebuilt from
+    // every item on disk.
+    fn replay(&self, saved: Option<SavedState>) -> Result<(Spaces, Deleted, ReindexReport)> {
+        let (mut spaces, mut deleted, next_keyspace, sequence, positions) = match saved {
+            Some(state) => (
+                state.spaces,
+                state.deleted,
+                state.next_keyspace,
+                state.sequence,
+                state.positions,
+            ),
+            None => (BTreeMap::new(), DeletedSet::new(), 0, 0, Vec::new()),
+        };
+
+        let state_ptr = self.state_ptr();
+        let mut report = ReindexReport::default();
+        let mut latest = BTreeMap::<(u32, Box<[u8]>), (FilePointer, u64, bool, Option<u64>)>::new();
+
+        for id in 0..self.strand_count() {
+            self.strand(id, |strand| {
+                let start = strand.start();
+                let end = strand.end();
+
+                // Skip what the saved state already covers, unless the
+                // strand has been vacuumed and rewritten since then
+                let from = match positions.get(id as usize) {
+                    Some(position) if position.generation == strand.generation() => {
+                        position.offset
+                    }
+                    _ => {
+                        for &mut (ref mut index, _) in spaces.values_mut() {
+                            let stale = index
+                                .iter()
+                                .filter(|&(_, &(ptr, _))| start <= ptr && ptr < end)
+                                .map(|(key, _)| key.clone())
+                                .collect::<Vec<_>>();
+
+                            for key in stale {
+                                index.remove(&key);
+                            }
+                        }
+
+                        let stale = deleted.range(start..end).cloned().collect::<Vec<_>>();
+                        for ptr in stale {
+                            deleted.remove(&ptr);
+                        }
+
+                        PAGE_SIZE64
+                    }
+                };
+
+                let stop = scan_items(strand, from, |ptr, ctx| {
+                    // Older checkpoints are garbage
+                    if ctx.is_state() {
+                        if Some(ptr) != state_ptr {
+                            deleted.insert(ptr);
+                        }
+
+                        return Ok(());
+                    }
+
+                    let key = (ctx.keyspace(), Vec::from(ctx.key()?).into_boxed_slice());
+                    let sequence = ctx.sequence();
+
+                    // Only the newest copy of each key is kept
+                    let newer = match latest.get(&key) {
+                        Some(&(_, seen, _, _)) => sequence > seen,
+                        None => true,
+                    };
+
+                    if newer {
+                        let value = (ptr, sequence, ctx.tombstone(), ctx.expires());
+
+                        if let Some((old_ptr, _, _, _)) = latest.insert(key, value) {
+                            deleted.insert(old_ptr);
+                        }
+                    } else {
+                        deleted.insert(ptr);
+                    }
+
+                    Ok(())
+                });
+
+                let data_end = start + strand.offset();
+                if stop < data_end {
+                    report.corrupt_strands += 1;
+                    report.skipped_bytes += data_end - stop;
+                }
+            });
+        }
+
+        let mut next_id = next_keyspace;
+
+        for ((keyspace, key), (ptr, seen, tombstone, expires)) in latest {
+            if keyspace != CATALOG && keyspace >= next_id {
+                next_id = keyspace + 1;
+            }
+
+            // Tombstones are never garbage collected while older copies of
+            // their keys remain, so vacuuming may have rewritten one after the
+            // state was saved. The state already accounts for its removal.
+            if tombstone && seen <= sequence {
+                deleted.insert(ptr);
+                continue;
+            }
+
+            let &mut (ref mut index, ref mut expiry) =
+                spaces.entry(keyspace).or_insert_with(Default::default);
+
+            // The expiry belongs to the newest copy of the key
+            match expires {
+                Some(time) if !tombstone => expiry.insert(key.clone(), time),
+                _ => expiry.remove(&key),
+            };
+
+            let old = if tombstone {
+                deleted.insert(ptr);
+                index.remove(&key)
+            } else {
+                deleted.remove(&ptr);
+                index.insert(key, (ptr, false))
+            };
+
+            if let Some((old_ptr, _)) = old {
+                if old_ptr != ptr {
+                    deleted.insert(old_ptr);
+                }
+            }
+        }
+
+        // Find which keyspaces still exist. Any others have been dropped,
+        // so whatever is left of them is garbage.
+        let mut names = BTreeMap::new();
+        if let Some(&(ref catalog, _)) = spaces.get(&CATALOG) {
+            for (name, &(ptr, _)) in catalog.iter() {
+                let id = self.read(ptr, |strand| {
+                    read_item(strand, ptr, true, |ctx| {
+                        let val = ctx.val()?;
+                        if val.len() != 4 {
+                            return Err(Error::Corrupt);
+                        }
+
+                        let mut id = 0;
+                        for i in 0..4 {
+                            id |= (val[i] as u32) << (i * 8);
+                        }
+
+                        Ok(id)
+                    })
+                })?;
+
+                let name = match String::from_utf8(Vec::from(&**name)) {
+                    Ok(name) => name,
+                    Err(_) => return Err(Error::Corrupt),
+                };
+
+                if id == DEFAULT_KEYSPACE || id == CATALOG {
+                    return Err(Error::Corrupt);
+                }
+
+                if id >= next_id {
+                    next_id = id + 1;
+                }
+
+                names.insert(name, id);
+            }
+        }
+
+        // A keyspace may not have had anything put in it yet
+        for &id in names.values() {
+            spaces.entry(id).or_insert_with(Default::default);
+        }
+
+        let dropped = spaces
+            .keys()
+            .cloned()
+            .filter(|&id| {
+                id != DEFAULT_KEYSPACE && id != CATALOG && !names.values().any(|&live| live == id)
+            })
+            .collect::<Vec<_>>();
+
+        for id in dropped {
+            if let Some((index, _)) = spaces.remove(&id) {
+                deleted.extend(index.values().map(|&(ptr, _)| ptr));
+            }
+        }
+
+        // Keys dropped along with a rewritten strand can't expire
+        for &mut (ref index, ref mut expiry) in spaces.values_mut() {
+            let kept = expiry
+                .iter()
+                .filter(|&(key, _)| index.contains_key(key))
+                .map(|(key, &time)| (key.clone(), time))
+                .collect();
+
+            *expiry = kept;
+        }
+
+        report.recovered_items = spaces
+            .iter()
+            .filter(|&(&id, _)| id != CATALOG)
+            .map(|(_, &(ref index, _))| index.len() as u64)
+            .sum();
+        report.deleted_items = deleted.len() as u64;
+
+        // The counts in the strand headers may be stale
+        for id in 0..self.strand_count() {
+            self.strand_mut(id, |strand| {
+                let start = strand.start();
+                let end = strand.end();
+                let stats = strand.stats.get_mut();
+
+                stats.valid_items = spaces
+                    .values()
+                    .map(|&(ref index, _)| {
+                        index
+                            .values()
+                            .filter(|&&(ptr, _)| start <= ptr && ptr < end)
+                            .count() as u64
+                    })
+                    .sum();
+                stats.deleted_items = deleted.range(start..end).count() as u64;
+            });
+        }
+
+        let next_id = if next_id > DEFAULT_KEYSPACE {
+            next_id
+        } else {
+            DEFAULT_KEYSPACE + 1
+        };
+
+        Ok((
+            Spaces::from(spaces, names, next_id),
+            Deleted::from(deleted),
+            report,
+        ))
+    }
+
     pub fn read<F, R>(&self, ptr: FilePointer, func: F) -> R
     where
         F: FnOnce(&Strand) -> R,
     {
-        self.0.rent(|strands| {
+        self.rental.rent(|strands| {
             // Search for the strand that has this file pointer
             let result = strands.binary_search_by(|strand| {
                 let guard = strand.read();
@@ -213,11 +490,16 @@ impl<'a> Volume<'a> {
     {
         let delay = Duration::new(0, 100 * 1000);
 
-        self.0.rent(|strands| {
+        self.rental.rent(|strands| {
             // Look for the first strand that is available for writing
             loop {
                 for ref strand in strands.iter() {
                     if let Some(mut guard) = strand.try_write_for(delay) {
+                        // Strands being vacuumed don't accept new items
+                        if guard.sealed() {
+                            continue;
+                        }
+
                         return func(&mut *guard);
                     }
                 }
@@ -225,10 +507,222 @@ impl<'a> Volume<'a> {
         })
     }
 
+    // Picks a strand for a write that can't hold its lock throughout,
+    // and passes its id to the closure. The strand is sealed until the
+    // closure returns, so that nothing else is written to it and it
+    // isn't vacuumed, but it can still be read from. The closure must
+    // lock the strand while it writes, and update its header when done.
+    pub fn reserve<F, R>(&self, func: F) -> R
+    where
+        F: FnOnce(u16) -> R,
+    {
+        let delay = Duration::new(0, 100 * 1000);
+
+        let mut id = self.try_reserve(delay);
+        while id.is_none() {
+            thread::sleep(delay);
+            id = self.try_reserve(delay);
+        }
+
+        let id = id.unwrap();
+        let result = func(id);
+        self.unseal(id);
+        result
+    }
+
+    fn try_reserve(&self, delay: Duration) -> Option<u16> {
+        self.rental.rent(|strands| {
+            let mut sealed = self.sealed.lock();
+            if *sealed + 1 >= strands.len() {
+                return None;
+            }
+
+            for ref strand in strands.iter() {
+                if let Some(mut guard) = strand.try_write_for(delay) {
+                    if guard.sealed() {
+                        continue;
+                    }
+
+                    guard.set_sealed(true);
+                    *sealed += 1;
+                    return Some(guard.id());
+                }
+            }
+
+            None
+        })
+    }
+
+    // Stops new items from being written to the strand. Returns false
+    // if it already was sealed, or if it is the last strand that isn't.
+    // Writes may be made while holding locks that whoever sealed a
+    // strand is waiting on, so one must always be left for them.
+    pub fn seal(&self, id: u16) -> bool {
+        let mut sealed = self.sealed.lock();
+        if *sealed + 1 >= self.strand_count() as usize {
+            return false;
+        }
+
+        let done = self.strand_mut(id, |strand| if strand.sealed() {
+            false
+        } else {
+            strand.set_sealed(true);
+            true
+        });
+
+        if done {
+            *sealed += 1;
+        }
+
+        done
+    }
+
+    pub fn unseal(&self, id: u16) {
+        let mut sealed = self.sealed.lock();
+        self.strand_mut(id, |strand| strand.set_sealed(false));
+        *sealed -= 1;
+    }
+
+    // The current sequence number, which is the highest
+    // one that has been given to an item so far.
+    #[inline]
+    pub fn sequence(&self) -> u64 {
+        *self.sequence.lock()
+    }
+
+    pub fn positions(&self) -> Vec<StrandPosition> {
+        (0..self.strand_count())
+            .map(|id| {
+                self.strand(id, |strand| {
+                    StrandPosition {
+                        generation: strand.generation(),
+                        offset: strand.offset(),
+                    }
+                })
+            })
+            .collect()
+    }
+
+    #[inline]
+    pub fn state_ptr(&self) -> Option<FilePointer> {
+        *self.state_ptr.lock()
+    }
+
+    // Writes the state to disk and points the volume header at it.
+    // Returns the pointer to the state it replaces, if any.
+    pub fn save_state(&self, state: DatastoreState) -> Result<Option<FilePointer>> {
+        let mut state_ptr = self.state_ptr.lock();
+        let ptr = self.write(|strand| state.write(strand))?;
+
+        self.write_header(Some(ptr))?;
+        Ok(mem::replace(&mut *state_ptr, Some(ptr)))
+    }
+
+    // Must be called before the given range is reset, so that
+    // the header does not point to a state that is gone. The next
+    // open will then reindex, unless a new state is saved first.
+    pub fn forget_state(&self, start: FilePointer, end: FilePointer) -> Result<()> {
+        let mut state_ptr = self.state_ptr.lock();
+
+        match *state_ptr {
+            Some(ptr) if start <= ptr && ptr < end => {
+                self.write_header(None)?;
+                *state_ptr = None;
+            }
+            _ => (),
+        }
+
+        Ok(())
+    }
+
+    // The header fits within a single sector,
+    // so the new pointer is written atomically.
+    fn write_header(&self, state_ptr: Option<FilePointer>) -> Result<()> {
+        let mut page = Page::default();
+        let header = VolumeHeader::new(self.strand_count(), state_ptr);
+        header.write(&mut page)?;
+
+        self.rental.head().write(0, &page[..])
+    }
+
+    // How many snapshots need the contents of each strand. Dropping a
+    // keyspace also pins every strand while its items are released.
+    // Holding the lock stops any strand from being reset.
+    #[inline]
+    pub fn pins(&self) -> MutexGuard<Vec<u32>> {
+        self.pins.lock()
+    }
+
+    #[inline]
+    pub fn pinned(&self, id: u16) -> bool {
+        self.pins.lock()[id as usize] > 0
+    }
+
+    // Runs the closure on the strand, unless it is pinned by a snapshot,
+    // in which case false is returned. The closure should reset the strand.
+    pub fn try_reset<F>(&self, id: u16, func: F) -> Result<bool>
+    where
+        F: FnOnce(&mut Strand) -> Result<()>,
+    {
+        let pins = self.pins.lock();
+        if pins[id as usize] > 0 {
+            return Ok(false);
+        }
+
+        self.strand_mut(id, func)?;
+        Ok(true)
+    }
+
+    // The range of pointers that each strand covers, in order
+    pub fn strand_ranges(&self) -> Vec<(FilePointer, FilePointer)> {
+        (0..self.strand_count())
+            .map(|id| self.strand(id, |strand| (strand.start(), strand.end())))
+            .collect()
+    }
+
+    #[inline]
+    pub fn strand_count(&self) -> u16 {
+        self.rental.rent(|strands| strands.len() as u16)
+    }
+
+    pub fn strand<F, R>(&self, id: u16, func: F) -> R
+    where
+        F: FnOnce(&Strand) -> R,
+    {
+        self.rental.rent(|strands| {
+            let guard = strands[id as usize].read();
+            func(&*guard)
+        })
+    }
+
+    pub fn strand_mut<F, R>(&self, id: u16, func: F) -> R
+    where
+        F: FnOnce(&mut Strand) -> R,
+    {
+        self.rental.rent(|strands| {
+            let mut guard = strands[id as usize].write();
+            func(&mut *guard)
+        })
+    }
+
+    // Writes the header of every strand, returning the first error
+    pub fn write_metadata(&self) -> Result<()> {
+        let mut result = Ok(());
+
+        for id in 0..self.strand_count() {
+            let written = self.strand_mut(id, |strand| strand.write_metadata());
+            if result.is_ok() {
+                result = written;
+            }
+        }
+
+        result
+    }
+
     pub fn stats(&self) -> Stats {
         let mut total_stats = Stats::default();
 
-        self.0.rent(|strands| for ref strand in strands.iter() {
+        self.rental.rent(|strands| for ref strand in strands.iter() {
             let guard = strand.read();
             let stats = guard.stats.lock();
             total_stats += stats.clone();
@@ -237,3 +731,11 @@ impl<'a> Volume<'a> {
         total_stats
     }
 }
+
+// The volume is shared with the vacuum thread through an Arc. The rental
+// struct holds the strands' borrows of the boxed device with their lifetime
+// erased, so thread safety is asserted here rather than inferred. This is
+// sound because devices are required to be Send + Sync, each strand is only
+// reached through its RwLock, and any other state is behind a Mutex.
+unsafe impl<'a> Send for Volume<'a> {}
+unsafe impl<'a> Sync for Volume<'a> {}
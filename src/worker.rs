@@ -0,0 +1,112 @@
+/*
+ * worker.rs
+ *
+ * striking-db - Persistent key/value store for SSDs.
+ * Copyright (c) 2017 Maxwell Duzen, Ammon Smith
+ *
+ * striking-db is free software: you can redistribute it and/or modify
+ * it under the terms of the GNU Lesser General Public License as
+ * published by the Free Software Foundation, either version 2 of
+ * the License, or (at your option) any later version.
+ *
+ * striking-db is distributed in the hope that it will be useful,
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+ * GNU General Public License for more details.
+ *
+ * You should have received a copy of the GNU Lesser General Public
+ * License along with striking-db.  If not, see <http://www.gnu.org/licenses/>.
+ *
+ */
+
+
+use super::Result;
+use parking_lot::{Condvar, Mutex};
+use std::fmt;
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+#[derive(Default)]
+pub struct Signal {
+    stopped: Mutex<bool>,
+    condvar: Condvar,
+}
+
+impl Signal {
+    pub fn stop(&self) {
+        *self.stopped.lock() = true;
+        self.condvar.notify_all();
+    }
+
+    pub fn stopped(&self) -> bool {
+        *self.stopped.lock()
+    }
+
+    // Waits for the given duration, returning early if stopped.
+    // Returns whether the signal has been stopped.
+    pub fn sleep(&self, duration: Duration) -> bool {
+        let deadline = Instant::now() + duration;
+        let mut stopped = self.stopped.lock();
+
+        while !*stopped {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+
+            self.condvar.wait_for(&mut stopped, deadline - now);
+        }
+
+        *stopped
+    }
+}
+
+// A background thread that runs a task once every interval, until it
+// is stopped or dropped. The task is given the signal, so that a long
+// pass can notice it should stop early.
+pub struct Worker {
+    signal: Arc<Signal>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Worker {
+    pub fn spawn<F>(interval: Duration, mut task: F) -> Self
+    where
+        F: FnMut(&Signal) -> Result<()> + Send + 'static,
+    {
+        let signal = Arc::new(Signal::default());
+        let handle = {
+            let signal = signal.clone();
+
+            thread::spawn(move || while !signal.sleep(interval) {
+                // Errors are left to be retried on the next pass
+                let _ = task(&signal);
+            })
+        };
+
+        Worker {
+            signal: signal,
+            handle: Some(handle),
+        }
+    }
+
+    pub fn stop(&mut self) {
+        if let Some(handle) = self.handle.take() {
+            self.signal.stop();
+            let _ = handle.join();
+        }
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+impl fmt::Debug for Worker {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Worker {{ .. }}")
+    }
+}
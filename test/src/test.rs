@@ -19,7 +19,85 @@
  *
  */
 
-use striking_db::Store;
+use std::cmp::min;
+use std::error::Error;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use striking_db::{self, Device, Memory, OpenOptions, Stats, Store, TypedStore, WriteBatch};
+
+// Opens a new datastore in memory, split into a few strands
+fn memory_store(options: &mut OpenOptions) -> Store {
+    options.create().strands(4);
+
+    let device = Box::new(Memory::new(16 * 1024 * 1024));
+    Store::with_device(device, options).expect("Opening memory store failed")
+}
+
+// Counts the writes and trims made to a device in memory,
+// which may be shared so that it can be opened again
+#[derive(Debug)]
+struct Counting {
+    inner: Arc<Memory>,
+    writes: Arc<AtomicUsize>,
+    trims: Arc<AtomicUsize>,
+}
+
+impl Counting {
+    fn new(inner: Arc<Memory>) -> Self {
+        Counting {
+            inner: inner,
+            writes: Arc::new(AtomicUsize::new(0)),
+            trims: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+}
+
+impl Device for Counting {
+    fn capacity(&self) -> u64 {
+        self.inner.capacity()
+    }
+
+    fn block_device(&self) -> bool {
+        false
+    }
+
+    fn read(&self, off: u64, buf: &mut [u8]) -> striking_db::Result<()> {
+        striking_db::check_read(self, off, buf);
+        self.inner.read(off, buf)
+    }
+
+    fn write(&self, off: u64, buf: &[u8]) -> striking_db::Result<()> {
+        striking_db::check_write(self, off, buf);
+        self.writes.fetch_add(1, Ordering::SeqCst);
+        self.inner.write(off, buf)
+    }
+
+    fn trim(&self, off: u64, len: u64) -> striking_db::Result<()> {
+        striking_db::check_trim(self, off, len);
+        self.trims.fetch_add(1, Ordering::SeqCst);
+        self.inner.trim(off, len)
+    }
+}
+
+// Puts a value under the key it is being streamed into, partway through
+struct Interrupting<'a> {
+    store: &'a Store,
+    inner: &'a [u8],
+    done: bool,
+}
+
+impl<'a> Read for Interrupting<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.done {
+            self.done = true;
+            self.store.put(b"race", b"put").expect("14 - Put failed");
+        }
+
+        self.inner.read(buf)
+    }
+}
 
 pub fn run(store: Store) {
     let mut value = [0; 16];
@@ -57,4 +135,430 @@ pub fn run(store: Store) {
         assert_eq!(store.exists(b"def"), false);
         store.lookup(b"def", &mut value[..]).expect_err("3 - Lookup succeeded");
     }
+
+    store.vacuum().expect("4 - Vacuum failed");
+    assert_eq!(store.stats().deleted_items, 0);
+    {
+        let len = store.lookup(b"abc", &mut value[..]).expect("4 - Lookup failed");
+        assert_eq!(b"111", &value[..len]);
+    }
+
+    store.checkpoint().expect("5 - Checkpoint failed");
+    store.put(b"ghi", b"222").expect("5 - Put failed");
+    store.checkpoint().expect("5 - Checkpoint failed");
+    {
+        let len = store.lookup(b"ghi", &mut value[..]).expect("5 - Lookup failed");
+        assert_eq!(b"222", &value[..len]);
+    }
+
+    {
+        let keys = store
+            .range(..)
+            .map(|item| item.expect("6 - Range failed").0)
+            .collect::<Vec<_>>();
+        assert_eq!(keys, vec![b"abc".to_vec(), b"ghi".to_vec()]);
+
+        let (key, _) = store.range(&b"abc"[..]..&b"ghi"[..])
+            .rev()
+            .next()
+            .expect("6 - Range was empty")
+            .expect("6 - Range failed");
+        assert_eq!(key, b"abc".to_vec());
+
+        let keys = store.keys_with_prefix(b"gh").collect::<Vec<_>>();
+        assert_eq!(keys, vec![b"ghi".to_vec()]);
+        assert_eq!(store.scan_prefix(b"x").count(), 0);
+    }
+
+    {
+        let mut batch = WriteBatch::new();
+        batch.put(b"jkl", b"333").remove(b"ghi");
+        store.write_batch(&batch).expect("7 - Batch failed");
+        assert_eq!(store.exists(b"ghi"), false);
+
+        let len = store.lookup(b"jkl", &mut value[..]).expect("7 - Lookup failed");
+        assert_eq!(b"333", &value[..len]);
+
+        batch.clear();
+        batch.put(b"mno", b"444").insert(b"abc", b"555");
+        store.write_batch(&batch).expect_err("7 - Batch succeeded");
+        assert_eq!(store.exists(b"mno"), false);
+    }
+
+    {
+        let val = store
+            .transaction(|txn| {
+                let val = txn.get(b"jkl")?.expect("8 - Item missing");
+                txn.put(b"pqr", &val)?;
+                txn.remove(b"jkl")?;
+                Ok(val)
+            })
+            .expect("8 - Transaction failed");
+        assert_eq!(val, b"333".to_vec());
+        assert_eq!(store.exists(b"jkl"), false);
+
+        let len = store.lookup(b"pqr", &mut value[..]).expect("8 - Lookup failed");
+        assert_eq!(b"333", &value[..len]);
+    }
+
+    {
+        let snapshot = store.snapshot();
+        store.put(b"pqr", b"666").expect("9 - Put failed");
+        store.remove(b"abc").expect("9 - Remove failed");
+        store.vacuum().expect("9 - Vacuum failed");
+
+        let len = snapshot.lookup(b"pqr", &mut value[..]).expect("9 - Lookup failed");
+        assert_eq!(b"333", &value[..len]);
+        assert_eq!(snapshot.exists(b"abc"), true);
+        assert_eq!(snapshot.range(..).count(), 2);
+    }
+
+    store
+        .compare_and_swap(b"lease", None, Some(b"a"))
+        .expect("10 - Swap failed");
+    store
+        .compare_and_swap(b"lease", None, Some(b"b"))
+        .expect_err("10 - Swap succeeded");
+    store
+        .compare_and_swap(b"lease", Some(b"a"), None)
+        .expect("10 - Swap failed");
+    assert_eq!(store.exists(b"lease"), false);
+
+    let result: Result<(), Box<Error>> = store.try_merge(b"pqr", |val| match val {
+        Some(_) => Err("11 - Rejected".into()),
+        None => Ok((None, ())),
+    });
+    result.expect_err("11 - Merge succeeded");
+    assert_eq!(store.exists(b"pqr"), true);
+
+    let result: Result<usize, Box<Error>> = store.try_merge(b"pqr", |val| {
+        let len = val.as_ref().map_or(0, |val| val.len());
+        Ok((val, len))
+    });
+    assert_eq!(result.expect("11 - Merge failed"), 3);
+
+    store
+        .put_with_ttl(b"session", b"444", Duration::from_secs(0))
+        .expect("12 - Put failed");
+    assert_eq!(store.exists(b"session"), false);
+    store
+        .lookup(b"session", &mut value)
+        .expect_err("12 - Lookup succeeded");
+    store.vacuum().expect("12 - Vacuum failed");
+    store.insert(b"session", b"555").expect("12 - Insert failed");
+
+    assert_eq!(store.value_len(b"session").expect("13 - Length failed"), 3);
+    assert_eq!(store.get(b"session").expect("13 - Get failed"), b"555");
+    {
+        let val = store.get_ref(b"session").expect("13 - Get failed");
+        assert_eq!(&*val, b"555");
+    }
+
+    let blob = vec![7; 100 * 1024];
+    store
+        .put_stream(b"blob", &blob[..], blob.len() as u64)
+        .expect("14 - Put failed");
+    store
+        .put_stream(b"short", &blob[..10], 20)
+        .expect_err("14 - Put succeeded");
+    assert_eq!(store.exists(b"short"), false);
+    {
+        let mut reader = store.open_value(b"blob").expect("14 - Open failed");
+        let mut read = Vec::new();
+        reader.read_to_end(&mut read).expect("14 - Read failed");
+        assert_eq!(read, blob);
+
+        reader.seek(SeekFrom::End(-4)).expect("14 - Seek failed");
+        reader.read_exact(&mut value[..4]).expect("14 - Read failed");
+        assert_eq!(&value[..4], &[7; 4]);
+
+        let len = store.lookup(b"blob", &mut value).expect("14 - Lookup failed");
+        assert_eq!(&value[..len], &[7; 16]);
+    }
+    {
+        let mut other = blob.clone();
+        other[80 * 1024] = 8;
+        store
+            .compare_and_swap(b"blob", Some(&other[..]), Some(&blob[..]))
+            .expect_err("14 - Swap succeeded");
+        store
+            .compare_and_swap(b"blob", Some(&blob[..]), Some(&blob[..]))
+            .expect("14 - Swap failed");
+    }
+    {
+        let reader = store.open_value(b"blob").expect("14 - Open failed");
+        store
+            .put_stream(b"copy", reader, blob.len() as u64)
+            .expect("14 - Copy failed");
+        assert_eq!(store.get(b"copy").expect("14 - Get failed"), blob);
+        store.remove(b"copy").expect("14 - Remove failed");
+    }
+    {
+        let reader = Interrupting {
+            store: &store,
+            inner: &blob[..],
+            done: false,
+        };
+        store
+            .put_stream(b"race", reader, blob.len() as u64)
+            .expect("14 - Put failed");
+        assert_eq!(store.get(b"race").expect("14 - Get failed"), blob);
+        store.remove(b"race").expect("14 - Remove failed");
+    }
+
+    let len = store
+        .read_range(b"blob", 1000, &mut value)
+        .expect("15 - Read failed");
+    assert_eq!(&value[..len], &[7; 16]);
+    let len = store
+        .read_range(b"pqr", 1, &mut value)
+        .expect("15 - Read failed");
+    assert_eq!(&value[..len], b"66");
+
+    // Ranges that cross from one chunk of a large value into the next
+    let pattern = (0..150 * 1024).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    store
+        .put_stream(b"pattern", &pattern[..], pattern.len() as u64)
+        .expect("15 - Put failed");
+    let mut range = vec![0; 1000];
+    for &offset in &[64 * 1024 - 500, 128 * 1024 - 1, 150 * 1024 - 600] {
+        let len = store
+            .read_range(b"pattern", offset as u64, &mut range)
+            .expect("15 - Read failed");
+        let end = min(offset + 1000, pattern.len());
+        assert_eq!(&range[..len], &pattern[offset..end]);
+    }
+    {
+        let mut reader = store.open_value(b"pattern").expect("15 - Open failed");
+        reader.seek(SeekFrom::Start(130 * 1024)).expect("15 - Seek failed");
+        reader.read_exact(&mut range).expect("15 - Read failed");
+        assert_eq!(&range[..], &pattern[130 * 1024..130 * 1024 + 1000]);
+
+        reader.seek(SeekFrom::Start(10)).expect("15 - Seek failed");
+        reader.read_exact(&mut range).expect("15 - Read failed");
+        assert_eq!(&range[..], &pattern[10..1010]);
+    }
+    store.remove(b"pattern").expect("15 - Remove failed");
+
+    {
+        let users = store.keyspace("users").expect("16 - Keyspace failed");
+        users.put(b"pqr", b"user").expect("16 - Put failed");
+        assert_eq!(users.get(b"pqr").expect("16 - Get failed"), b"user");
+        assert_eq!(store.get(b"pqr").expect("16 - Get failed"), b"666");
+
+        store.drop_keyspace("users").expect("16 - Drop failed");
+        users.put(b"pqr", b"user").expect_err("16 - Put succeeded");
+
+        let users = store.keyspace("users").expect("16 - Keyspace failed");
+        assert_eq!(users.exists(b"pqr"), false);
+    }
+
+    {
+        let space = store.keyspace("scores").expect("17 - Keyspace failed");
+        let scores = TypedStore::<(String, u32), i64>::new(&space);
+        scores.put(&(String::from("b"), 1), &-5).expect("17 - Put failed");
+        scores.put(&(String::from("a"), 10), &7).expect("17 - Put failed");
+        scores.put(&(String::from("a"), 9), &3).expect("17 - Put failed");
+        assert_eq!(scores.get(&(String::from("b"), 1)).expect("17 - Get failed"), -5);
+
+        let pairs = TypedStore::<(String, u32), (i64, i64)>::new(&space);
+        pairs.delete(&(String::from("b"), 1)).expect_err("17 - Delete succeeded");
+        assert_eq!(scores.get(&(String::from("b"), 1)).expect("17 - Get failed"), -5);
+
+        let keys = scores
+            .scan_prefix("a")
+            .expect("17 - Scan failed")
+            .map(|item| {
+                let ((_, id), _) = item.expect("17 - Read failed");
+                id
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(keys, vec![9, 10]);
+    }
+
+    {
+        let mut options = OpenOptions::new();
+        options.read_cache(64 * 1024).read_cache_max_value(1024);
+
+        let small = memory_store(&mut options);
+        let val = [1; 1000];
+        small.put(b"hot", &val).expect("18 - Put failed");
+        for i in 0..200 {
+            let key = format!("item{:04}", i);
+            small.put(key.as_bytes(), &val).expect("18 - Put failed");
+        }
+
+        // Reads that opted out of caching don't add anything to it
+        let before = small.stats();
+        {
+            let bulk = small.uncached();
+            assert_eq!(bulk.get(b"item0000").expect("18 - Get failed"), &val[..]);
+            assert_eq!(bulk.range(..).count(), 201);
+        }
+        assert_eq!(small.range(..).fill_cache(false).count(), 201);
+        assert_eq!(small.stats().cache_insertions, before.cache_insertions);
+
+        // A scan of more than the cache holds doesn't push out an item in use
+        for _ in 0..10 {
+            small.get(b"hot").expect("18 - Get failed");
+        }
+
+        assert_eq!(small.range(..).count(), 201);
+        let before = small.stats();
+        small.get(b"hot").expect("18 - Get failed");
+        assert_eq!(small.stats().cache_hits, before.cache_hits + 1);
+        small.close().expect("18 - Close failed");
+    }
+
+    {
+        let before = store.stats();
+        store.get(b"pqr").expect("19 - Get failed");
+        store.get(b"pqr").expect("19 - Get failed");
+
+        let after = store.stats();
+        assert!(after.cache_hits > before.cache_hits);
+        let lookups = |stats: &Stats| stats.cache_hits + stats.cache_misses;
+        assert!(lookups(&after) >= lookups(&before) + 2);
+        assert!(after.cache_bytes > 0);
+
+        // Values stored unpacked are cached like any other that fits
+        let mid = vec![5; 5000];
+        store.put(b"mid", &mid).expect("19 - Put failed");
+        assert_eq!(store.get(b"mid").expect("19 - Get failed"), mid);
+        let before = store.stats();
+        assert_eq!(store.get(b"mid").expect("19 - Get failed"), mid);
+        assert_eq!(store.stats().cache_hits, before.cache_hits + 1);
+        store.remove(b"mid").expect("19 - Remove failed");
+
+        // A delete uses the cached value, and never puts it back
+        store.put(b"del", b"777").expect("19 - Put failed");
+        store.get(b"del").expect("19 - Get failed");
+        let before = store.stats();
+        let len = store.delete(b"del", &mut value).expect("19 - Delete failed");
+        assert_eq!(&value[..len], b"777");
+
+        let after = store.stats();
+        assert_eq!(after.cache_hits, before.cache_hits + 1);
+        assert_eq!(after.cache_insertions, before.cache_insertions);
+        assert!(after.cache_bytes < before.cache_bytes);
+
+        store.put(b"del", b"888").expect("19 - Put failed");
+        let before = store.stats();
+        let len = store.delete(b"del", &mut value).expect("19 - Delete failed");
+        assert_eq!(&value[..len], b"888");
+
+        let after = store.stats();
+        assert_eq!(after.cache_misses, before.cache_misses + 1);
+        assert_eq!(after.cache_insertions, before.cache_insertions);
+        store.lookup(b"del", &mut value).expect_err("19 - Lookup succeeded");
+    }
+
+    {
+        let memory = memory_store(&mut OpenOptions::new());
+        memory.insert(b"abc", b"def").expect("20 - Insert failed");
+        assert_eq!(memory.get(b"abc").expect("20 - Get failed"), b"def");
+        memory.close().expect("20 - Close failed");
+    }
+    {
+        // A whole number of pages, but not of trim blocks
+        let counting = Counting::new(Arc::new(Memory::new(16 * 1024 * 1024 + 4096)));
+        let (writes, trims) = (counting.writes.clone(), counting.trims.clone());
+
+        let mut options = OpenOptions::new();
+        options.truncate().strands(4);
+        let custom = Store::with_device(Box::new(counting), &options).expect("20 - Open failed");
+        assert!(trims.load(Ordering::SeqCst) > 0);
+
+        let before = writes.load(Ordering::SeqCst);
+        custom.insert(b"abc", b"def").expect("20 - Insert failed");
+        assert_eq!(custom.get(b"abc").expect("20 - Get failed"), b"def");
+        assert!(writes.load(Ordering::SeqCst) > before);
+        custom.close().expect("20 - Close failed");
+
+        let misaligned = Box::new(Memory::new(16 * 1024 * 1024 + 100));
+        match Store::with_device(misaligned, &options) {
+            Err(striking_db::Error::BadArgument(_)) => (),
+            Err(err) => panic!("20 - Open failed with {}", err),
+            Ok(_) => panic!("20 - Open succeeded"),
+        }
+    }
+
+    {
+        let spread = memory_store(&mut OpenOptions::new());
+
+        // Vacuuming moves "keep" out of the first strand,
+        // so the items after it are spread over two strands
+        spread.put(b"keep", b"1").expect("21 - Put failed");
+        spread.put(b"tmp", b"2").expect("21 - Put failed");
+        spread.remove(b"tmp").expect("21 - Remove failed");
+        spread.vacuum().expect("21 - Vacuum failed");
+
+        spread.put(b"gone", b"3").expect("21 - Put failed");
+        spread.remove(b"gone").expect("21 - Remove failed");
+        spread.vacuum().expect("21 - Vacuum failed");
+        assert_eq!(spread.stats().deleted_items, 0);
+        assert_eq!(spread.get(b"keep").expect("21 - Get failed"), b"1");
+        assert_eq!(spread.exists(b"gone"), false);
+        spread.close().expect("21 - Close failed");
+    }
+
+    {
+        let memory = Arc::new(Memory::new(16 * 1024 * 1024));
+        let open = |options: &OpenOptions| {
+            let device = Box::new(Counting::new(memory.clone()));
+            Store::with_device(device, options).expect("22 - Open failed")
+        };
+
+        let first = open(OpenOptions::new().create().strands(4));
+        first.put(b"old", b"1").expect("22 - Put failed");
+        first.put(b"gone", b"2").expect("22 - Put failed");
+        first.checkpoint().expect("22 - Checkpoint failed");
+
+        first.put(b"new", b"3").expect("22 - Put failed");
+        first.put(b"old", b"4").expect("22 - Put failed");
+        first.remove(b"gone").expect("22 - Remove failed");
+        {
+            let kept = first.keyspace("kept").expect("22 - Keyspace failed");
+            kept.put(b"old", b"5").expect("22 - Put failed");
+
+            let dropped = first.keyspace("dropped").expect("22 - Keyspace failed");
+            dropped.put(b"new", b"6").expect("22 - Put failed");
+        }
+        first.drop_keyspace("dropped").expect("22 - Drop failed");
+        first.close().expect("22 - Close failed");
+
+        let check = |reopened: &Store| {
+            assert_eq!(reopened.get(b"old").expect("22 - Get failed"), b"4");
+            assert_eq!(reopened.get(b"new").expect("22 - Get failed"), b"3");
+            assert_eq!(reopened.exists(b"gone"), false);
+
+            let kept = reopened.keyspace("kept").expect("22 - Keyspace failed");
+            assert_eq!(kept.get(b"old").expect("22 - Get failed"), b"5");
+            assert_eq!(kept.exists(b"new"), false);
+        };
+
+        let read = open(&OpenOptions::new());
+        assert!(read.reindex_report().is_none());
+        check(&read);
+        read.close().expect("22 - Close failed");
+
+        let reindexed = open(OpenOptions::new().reindex());
+        {
+            let report = reindexed.reindex_report().expect("22 - No reindex report");
+            assert_eq!(report.recovered_items, 3);
+            assert_eq!(report.deleted_items, 6);
+            assert_eq!(report.corrupt_strands, 0);
+        }
+        check(&reindexed);
+
+        // Opening it again starts an empty keyspace under the same name
+        {
+            let dropped = reindexed.keyspace("dropped").expect("22 - Keyspace failed");
+            assert_eq!(dropped.exists(b"new"), false);
+            assert_eq!(dropped.keys(..).count(), 0);
+        }
+        reindexed.close().expect("22 - Close failed");
+    }
+
+    store.close().expect("23 - Close failed");
 }